@@ -0,0 +1,98 @@
+//! point reads from several threads at once, with and without a writer flushing memtables
+//!
+//! run with `cargo bench --bench concurrent_reads`
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use kv::{memtable::MemTable, options::Options};
+
+const KEYS: u64 = 100_000;
+const RUN_TIME: Duration = Duration::from_secs(2);
+
+fn key(i: u64) -> String {
+    format!("key{:08}", i % KEYS)
+}
+
+// gets per second over all reader threads
+fn run(memtable: &Arc<MemTable>, readers: u64, with_writer: bool) -> f64 {
+    let stop = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+
+    let writer = with_writer.then(|| {
+        let memtable = memtable.clone();
+        let stop = stop.clone();
+
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                memtable.put(key(i * 7919), vec![b'w'; 100]).unwrap();
+                i += 1;
+            }
+        })
+    });
+
+    let handles: Vec<_> = (0..readers)
+        .map(|reader| {
+            let memtable = memtable.clone();
+            let stop = stop.clone();
+            let reads = reads.clone();
+
+            thread::spawn(move || {
+                let mut i = reader * 104_729;
+                let mut done = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    memtable.get(&key(i)).unwrap().expect("key is loaded");
+                    i += 15_485_863;
+                    done += 1;
+                }
+                reads.fetch_add(done, Ordering::Relaxed);
+            })
+        })
+        .collect();
+
+    let started = Instant::now();
+    thread::sleep(RUN_TIME);
+    stop.store(true, Ordering::Relaxed);
+    for handle in handles.into_iter().chain(writer) {
+        handle.join().unwrap();
+    }
+
+    reads.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.path().to_path_buf(),
+        memtable_size_bytes: 1024 * 1024,
+        ..Options::default()
+    });
+    memtable.startup().unwrap();
+
+    for i in 0..KEYS {
+        memtable.put(key(i), vec![b'v'; 100]).unwrap();
+    }
+    let memtable = Arc::new(memtable);
+
+    println!(
+        "{} cpus, {KEYS} keys, {}s per run",
+        thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+        RUN_TIME.as_secs()
+    );
+    for with_writer in [false, true] {
+        for readers in [1, 2, 4, 8] {
+            let gets = run(&memtable, readers, with_writer);
+            println!(
+                "{readers} readers{}: {gets:.0} gets/s",
+                if with_writer { " + 1 writer" } else { "" }
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{
+    memtable::{Key, SstEntry, Value},
+    merge,
+};
+
+/// puts and deletes applied atomically
+///
+/// the whole batch is one wal record, so recovery replays all of it or none of it,
+/// and reads see all of it or none of it. later writes to the same key win
+///
+/// preconditions are checked on commit, against the latest committed versions,
+/// and the batch is rejected as a whole if any of them fails
+#[derive(Clone, Default)]
+pub struct WriteBatch {
+    entries: Vec<SstEntry>,
+    preconditions: Vec<(Key, Precondition)>,
+}
+
+/// condition on the version of a key, a deleted or expired key counts as missing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Precondition {
+    Exists,
+    Missing,
+    /// the key exists at one of these versions
+    VersionIn(Vec<u64>),
+    /// the key is missing, or at none of these versions
+    VersionNotIn(Vec<u64>),
+    /// the newest write to the key, tombstones and expired puts included, has this sequence number
+    LastWrite(u64),
+    /// no write to the key, tombstones and expired puts included, is numbered after this
+    UnchangedSince(u64),
+}
+
+impl Precondition {
+    /// `latest` is the newest entry of the key, with merge operands folded in
+    pub fn holds(&self, latest: Option<&SstEntry>) -> bool {
+        let version = latest.and_then(SstEntry::version);
+
+        match self {
+            Self::Exists => version.is_some(),
+            Self::Missing => version.is_none(),
+            Self::VersionIn(versions) => version.is_some_and(|version| versions.contains(&version)),
+            Self::VersionNotIn(versions) => {
+                version.is_none_or(|version| !versions.contains(&version))
+            }
+            Self::LastWrite(seq) => latest.is_some_and(|latest| latest.seq() == *seq),
+            Self::UnchangedSince(seq) => latest.is_none_or(|latest| latest.seq() <= *seq),
+        }
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Key, value: Value) {
+        self.entries.push(SstEntry::new_put(key, value));
+    }
+
+    /// a put that reads as missing from `expires_at` on, in unix milliseconds
+    pub fn put_expiring(&mut self, key: Key, value: Value, expires_at: u64) {
+        self.entries
+            .push(SstEntry::new_expiring_put(key, value, expires_at));
+    }
+
+    pub fn delete(&mut self, key: Key) {
+        self.entries.push(SstEntry::new_delete(key));
+    }
+
+    /// adds `by` to the integer stored at `key`, see `MemTable::increment`
+    pub fn increment(&mut self, key: Key, by: i64) {
+        self.merge(key, merge::ADD, merge::increment_operand(by));
+    }
+
+    /// applies `operand` with the merge operator named `operator`, see `MemTable::merge`
+    pub fn merge(&mut self, key: Key, operator: &str, operand: Value) {
+        self.entries
+            .push(SstEntry::new_merge(key, operator, operand));
+    }
+
+    /// adds a write made by hand, such as one from `SstEntry::new_expiring_put`
+    pub fn add(&mut self, entry: SstEntry) {
+        self.entries.push(entry);
+    }
+
+    /// rejects the batch unless `precondition` holds for `key`
+    pub fn require(&mut self, key: Key, precondition: Precondition) {
+        self.preconditions.push((key, precondition));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[SstEntry] {
+        &self.entries
+    }
+
+    pub fn preconditions(&self) -> &[(Key, Precondition)] {
+        &self.preconditions
+    }
+
+    // numbers the entries from `first` on, in batch order
+    pub(crate) fn set_seqs(&mut self, first: u64) {
+        for (seq, entry) in (first..).zip(&mut self.entries) {
+            entry.set_seq(seq);
+        }
+    }
+}
+
+impl From<SstEntry> for WriteBatch {
+    fn from(entry: SstEntry) -> Self {
+        Self {
+            entries: vec![entry],
+            preconditions: Vec::new(),
+        }
+    }
+}
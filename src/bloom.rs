@@ -0,0 +1,90 @@
+use crate::error::{KvError, Result};
+
+/// bloom filter over the keys of one sst file
+///
+/// probes are derived from a single 64 bit hash using double hashing,
+/// so the hash must stay stable across releases since filters are persisted
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    const MAX_HASH_COUNT: u32 = 30;
+
+    pub fn build(hashes: &[u64], false_positive_rate: f64) -> Self {
+        let key_count = hashes.len().max(1) as f64;
+        let false_positive_rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 1.0);
+
+        // optimal sizing: m = -n ln(p) / ln(2)^2, k = m / n * ln(2)
+        let bit_count = (-key_count * false_positive_rate.ln() / std::f64::consts::LN_2.powi(2))
+            .ceil()
+            .max(8.0) as usize;
+        let hash_count = ((bit_count as f64 / key_count) * std::f64::consts::LN_2).round() as u32;
+
+        let mut filter = Self {
+            bits: vec![0; bit_count.div_ceil(8)],
+            hash_count: hash_count.clamp(1, Self::MAX_HASH_COUNT),
+        };
+
+        for hash in hashes {
+            filter.insert(*hash);
+        }
+
+        filter
+    }
+
+    pub fn hash(key: &[u8]) -> u64 {
+        // 64 bit fnv-1a
+        key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let hash = Self::hash(key);
+
+        Self::probes(hash, self.hash_count, self.bit_count())
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.hash_count.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let Some((hash_count, bits)) = buf.split_first_chunk::<4>() else {
+            return Err(KvError::InvalidSst("bloom filter too small".to_string()));
+        };
+
+        let hash_count = u32::from_le_bytes(*hash_count);
+
+        if bits.is_empty() || hash_count == 0 || hash_count > Self::MAX_HASH_COUNT {
+            return Err(KvError::InvalidSst("malformed bloom filter".to_string()));
+        }
+
+        Ok(Self {
+            bits: bits.to_vec(),
+            hash_count,
+        })
+    }
+
+    fn insert(&mut self, hash: u64) {
+        for bit in Self::probes(hash, self.hash_count, self.bit_count()) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    fn bit_count(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    fn probes(hash: u64, hash_count: u32, bit_count: u64) -> impl Iterator<Item = usize> {
+        let low = hash as u32 as u64;
+        let high = hash >> 32;
+
+        (0..hash_count as u64)
+            .map(move |i| (low.wrapping_add(i.wrapping_mul(high)) % bit_count) as usize)
+    }
+}
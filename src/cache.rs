@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{sst::Block, stats::Stats};
+
+/// decoded sst data blocks, keyed by file id and block offset
+///
+/// holds at most `capacity` bytes, evicting the least recently used block first.
+/// shared by every open sst file, each of which gets its own id from the cache
+/// and drops its blocks once the file is closed or deleted
+pub struct BlockCache {
+    capacity: u64,
+    // concurrency safety:
+    // lookups, inserts and evictions each hold it briefly,
+    // blocks are read and decoded outside of it
+    state: Mutex<State>,
+    next_file_id: AtomicU64,
+    stats: Arc<Stats>,
+}
+
+#[derive(Default)]
+struct State {
+    blocks: BTreeMap<(u64, u64), Cached>,
+    // block of every use tick, least recently used first
+    recency: BTreeMap<u64, (u64, u64)>,
+    tick: u64,
+    size: u64,
+}
+
+struct Cached {
+    block: Arc<Block>,
+    last_used: u64,
+}
+
+impl BlockCache {
+    /// a cache of at most `capacity` bytes, 0 to cache nothing
+    pub fn new(capacity: u64, stats: Arc<Stats>) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State::default()),
+            next_file_id: AtomicU64::new(1),
+            stats,
+        }
+    }
+
+    /// bytes of the blocks held
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    /// an id no other file opened since startup has
+    pub fn new_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn get(&self, file_id: u64, offset: u64) -> Option<Arc<Block>> {
+        let mut state = self.state.lock().unwrap();
+        let block = state.touch((file_id, offset));
+
+        match &block {
+            Some(_) => self.stats.record_block_cache_hit(),
+            None => self.stats.record_block_cache_miss(),
+        }
+
+        block
+    }
+
+    /// adds a block, evicting others until the cache is back within its capacity
+    pub fn insert(&self, file_id: u64, offset: u64, block: Arc<Block>) {
+        let size = block.size();
+        if size > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let key = (file_id, offset);
+
+        // another reader of the same block got here first
+        if state.touch(key).is_some() {
+            return;
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.blocks.insert(
+            key,
+            Cached {
+                block,
+                last_used: tick,
+            },
+        );
+        state.recency.insert(tick, key);
+        state.size += size;
+
+        while state.size > self.capacity
+            && let Some((_, key)) = state.recency.pop_first()
+        {
+            state.remove(key);
+        }
+    }
+
+    /// drops every block of a file
+    pub fn invalidate(&self, file_id: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        let keys: Vec<_> = state
+            .blocks
+            .range((file_id, 0)..=(file_id, u64::MAX))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            if let Some(last_used) = state.remove(key) {
+                state.recency.remove(&last_used);
+            }
+        }
+    }
+}
+
+impl State {
+    // marks a block as the most recently used
+    fn touch(&mut self, key: (u64, u64)) -> Option<Arc<Block>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let cached = self.blocks.get_mut(&key)?;
+        self.recency.remove(&cached.last_used);
+        self.recency.insert(tick, key);
+        cached.last_used = tick;
+
+        Some(cached.block.clone())
+    }
+
+    // returns when the block was last used, its recency entry is left to the caller
+    fn remove(&mut self, key: (u64, u64)) -> Option<u64> {
+        let cached = self.blocks.remove(&key)?;
+        self.size -= cached.block.size();
+
+        Some(cached.last_used)
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Condvar, Mutex},
+};
+
+use crate::error::Result;
+
+/// lets concurrent writers share one wal write and fsync
+///
+/// a writer queues its item, then either becomes the leader and commits
+/// everything queued so far in one go, or waits for the current leader to do it.
+/// each item gets its own result, so one rejected item does not fail the rest
+pub struct GroupCommit<T, R> {
+    state: Mutex<State<T, R>>,
+    // wakes waiting writers once a leader is done
+    committed: Condvar,
+}
+
+struct State<T, R> {
+    queue: Vec<T>,
+    // ids are handed out in queue order, every id below `committed` is done
+    next_id: u64,
+    committed: u64,
+    leader: bool,
+    // result of each committed item, taken by its writer
+    results: HashMap<u64, Result<R>>,
+}
+
+impl<T, R> GroupCommit<T, R> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                queue: Vec::new(),
+                next_id: 0,
+                committed: 0,
+                leader: false,
+                results: HashMap::new(),
+            }),
+            committed: Condvar::new(),
+        }
+    }
+
+    /// returns once `item` is committed, by this thread or by another leader
+    ///
+    /// `commit` only runs on the leader, with items in the order they were queued,
+    /// and never concurrently with itself. it returns one result per item, in the same order
+    pub fn submit(&self, item: T, commit: impl FnOnce(Vec<T>) -> Vec<Result<R>>) -> Result<R> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push(item);
+
+        while state.leader {
+            state = self.committed.wait(state).unwrap();
+
+            if id < state.committed {
+                return state
+                    .results
+                    .remove(&id)
+                    .expect("every committed item has a result");
+            }
+        }
+
+        // the item is still queued, so this thread leads the next batch
+        state.leader = true;
+        let batch = mem::take(&mut state.queue);
+        let (start, end) = (state.committed, state.next_id);
+        drop(state);
+
+        let results = commit(batch);
+        debug_assert_eq!(results.len() as u64, end - start);
+
+        let mut state = self.state.lock().unwrap();
+        state.results.extend((start..end).zip(results));
+        state.committed = end;
+        state.leader = false;
+        self.committed.notify_all();
+
+        state
+            .results
+            .remove(&id)
+            .expect("every committed item has a result")
+    }
+}
+
+impl<T, R> Default for GroupCommit<T, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        mpsc::{self, TryRecvError},
+    },
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime},
+};
+
+use crate::{
+    error::Result,
+    iterator::MergeIterator,
+    manifest::{Level, Manifest},
+    memtable::{Key, SstEntry, unix_millis},
+    merge::MergeOperators,
+    options::{CompactionStyle, Options},
+    snapshot::Snapshots,
+    sst::{Sst, SstWriter},
+    stats::Stats,
+};
+
+/// runs compactions on a background thread, off the write path
+pub struct Compactor {
+    sender: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub fn start(
+        manifest: Arc<Manifest>,
+        operators: Arc<MergeOperators>,
+        snapshots: Arc<Snapshots>,
+        options: Options,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let mut strategy = options.compaction_style.strategy();
+
+            while receiver.recv().is_ok() {
+                // keeps going until the strategy has nothing left to do,
+                // which also serves requests that piled up meanwhile
+                loop {
+                    if let Err(TryRecvError::Disconnected) = receiver.try_recv() {
+                        return;
+                    }
+
+                    match strategy.compact(&manifest, &operators, &snapshots, &options, &stats) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(e) => {
+                            println!("[ERROR] compaction: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// asks for a compaction without waiting for it
+    pub fn trigger(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel stops the worker once the current run finishes
+        self.sender.take();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// decides which files to merge and where the outputs go
+///
+/// every strategy works from a snapshot of the manifest, so flushes can carry on meanwhile,
+/// and only locks the manifest to swap the files at the end
+pub trait CompactionStrategy: Send {
+    /// runs one compaction, false if there is nothing to do
+    ///
+    /// merge operands are folded with `operators` as files are merged,
+    /// and versions read by `snapshots` are kept
+    fn compact(
+        &mut self,
+        manifest: &Manifest,
+        operators: &MergeOperators,
+        snapshots: &Snapshots,
+        options: &Options,
+        stats: &Stats,
+    ) -> Result<bool>;
+}
+
+impl CompactionStyle {
+    pub fn strategy(self) -> Box<dyn CompactionStrategy> {
+        match self {
+            Self::Leveled => Box::new(LeveledCompaction::default()),
+            Self::SizeTiered => Box::new(SizeTieredCompaction),
+            Self::Full => Box::new(FullCompaction),
+        }
+    }
+}
+
+/// merges every file into a single sorted run in level 1
+///
+/// every compaction rewrites the whole tree, so it only pays off for small datasets
+pub struct FullCompaction;
+
+impl CompactionStrategy for FullCompaction {
+    // runs once per trigger: with nothing flushed since, the tree is already one run
+    fn compact(
+        &mut self,
+        manifest: &Manifest,
+        operators: &MergeOperators,
+        snapshots: &Snapshots,
+        options: &Options,
+        stats: &Stats,
+    ) -> Result<bool> {
+        let started = Instant::now();
+        let levels = manifest.levels();
+
+        if levels.first().is_none_or(|files| files.is_empty()) {
+            return Ok(false);
+        }
+
+        // every file is part of this merge, so nothing older is left
+        let compacted: Vec<_> = levels.into_iter().rev().flatten().collect();
+        let outputs = merge(
+            manifest,
+            operators,
+            snapshots,
+            options,
+            &compacted,
+            &[],
+            options.memtable_size_bytes,
+        )?;
+
+        finish(manifest, stats, started, &compacted, outputs, 1)?;
+
+        Ok(true)
+    }
+}
+
+/// flushed files pile up in level 0, each deeper level may hold
+/// `level_size_multiplier` times more bytes than the one above it
+///
+/// a level over its target pushes files one level down, merging them only with
+/// the overlapping files there, so a compaction rewrites a slice of the tree rather than all of it
+#[derive(Default)]
+pub struct LeveledCompaction {
+    // largest key compacted out of each level, the next pick from that level starts after it
+    cursors: Vec<Option<Key>>,
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    // false once every level is within its target
+    fn compact(
+        &mut self,
+        manifest: &Manifest,
+        operators: &MergeOperators,
+        snapshots: &Snapshots,
+        options: &Options,
+        stats: &Stats,
+    ) -> Result<bool> {
+        let started = Instant::now();
+        let levels = manifest.levels();
+
+        let Some(level) = Self::pick_level(&levels, options) else {
+            return Ok(false);
+        };
+        let output_level = level + 1;
+
+        // level 0 files overlap, so they all go down together
+        let inputs = match level {
+            0 => levels[0].clone(),
+            _ => vec![self.pick_file(level, &levels[level])],
+        };
+        let range = key_range(&inputs);
+        let overlapping: Vec<_> = levels
+            .get(output_level)
+            .into_iter()
+            .flatten()
+            .filter(|sst| overlaps(sst, range.as_ref()))
+            .cloned()
+            .collect();
+
+        // nothing to merge with, the file moves down as-is
+        if level > 0 && overlapping.is_empty() {
+            manifest.replace(&inputs, inputs.clone(), output_level)?;
+            return Ok(true);
+        }
+
+        // only deeper levels hold older versions, the rest of the input level is disjoint
+        let older: Vec<_> = levels
+            .iter()
+            .skip(output_level + 1)
+            .flatten()
+            .filter(|sst| overlaps(sst, range.as_ref()))
+            .cloned()
+            .collect();
+
+        // sources go oldest to newest, and the output level is older than the input level
+        let compacted: Vec<_> = overlapping.into_iter().chain(inputs).collect();
+        let outputs = merge(
+            manifest,
+            operators,
+            snapshots,
+            options,
+            &compacted,
+            &older,
+            options.memtable_size_bytes,
+        )?;
+
+        finish(manifest, stats, started, &compacted, outputs, output_level)?;
+
+        Ok(true)
+    }
+}
+
+impl LeveledCompaction {
+    // level with the highest score of at least 1, the last level never compacts
+    fn pick_level(levels: &[Level], options: &Options) -> Option<usize> {
+        levels
+            .iter()
+            .enumerate()
+            .take(options.max_levels.saturating_sub(1))
+            .filter_map(|(level, files)| {
+                let score = match level {
+                    0 => files.len() as f64 / options.level0_file_limit as f64,
+                    _ => {
+                        let bytes: u64 = files.iter().map(|sst| sst.size()).sum();
+                        bytes as f64 / level_target_bytes(options, level) as f64
+                    }
+                };
+
+                (score >= 1.0).then_some((level, score))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(level, _)| level)
+    }
+
+    // round-robins through the level by key, so every part of it gets pushed down in turn
+    fn pick_file(&mut self, level: usize, files: &[Arc<Sst>]) -> Arc<Sst> {
+        if self.cursors.len() <= level {
+            self.cursors.resize(level + 1, None);
+        }
+
+        let cursor = &mut self.cursors[level];
+        let file = files
+            .iter()
+            .find(|sst| {
+                cursor
+                    .as_ref()
+                    .is_none_or(|cursor| sst.key_range().is_some_and(|(first, _)| first > cursor))
+            })
+            .unwrap_or(&files[0])
+            .clone();
+
+        *cursor = file.key_range().map(|(_, last)| last.clone());
+
+        file
+    }
+}
+
+/// every file is a sorted run in level 0, runs of similar size are merged into one bigger run
+///
+/// each entry is rewritten about once per size tier, which suits write-heavy workloads,
+/// at the cost of reads checking more files
+pub struct SizeTieredCompaction;
+
+impl CompactionStrategy for SizeTieredCompaction {
+    // false once no `size_tier_min_runs` adjacent runs are of similar size
+    fn compact(
+        &mut self,
+        manifest: &Manifest,
+        operators: &MergeOperators,
+        snapshots: &Snapshots,
+        options: &Options,
+        stats: &Stats,
+    ) -> Result<bool> {
+        let started = Instant::now();
+        let levels = manifest.levels();
+        let Some(runs) = levels.first() else {
+            return Ok(false);
+        };
+
+        let Some(tier) = Self::pick_tier(runs, options) else {
+            return Ok(false);
+        };
+
+        // runs older than the tier, and anything in deeper levels, hold older versions
+        let older: Vec<_> = runs[..tier.start]
+            .iter()
+            .chain(levels[1..].iter().flatten())
+            .cloned()
+            .collect();
+
+        // runs are already oldest to newest, the output is a single run
+        let compacted = &runs[tier];
+        let outputs = merge(
+            manifest,
+            operators,
+            snapshots,
+            options,
+            compacted,
+            &older,
+            u64::MAX,
+        )?;
+
+        finish(manifest, stats, started, compacted, outputs, 0)?;
+
+        Ok(true)
+    }
+}
+
+impl SizeTieredCompaction {
+    // oldest window of adjacent runs whose sizes are all within `size_tier_ratio` of each other,
+    // made as long as it can be.
+    // only adjacent runs may merge, so the newer runs stay newer than the output
+    fn pick_tier(runs: &[Arc<Sst>], options: &Options) -> Option<std::ops::Range<usize>> {
+        let min_runs = options.size_tier_min_runs.max(2);
+        let sizes: Vec<_> = runs.iter().map(|run| run.size().max(1)).collect();
+
+        // every start is tried, a window may begin anywhere, not only after a jump in size
+        (0..sizes.len()).find_map(|start| {
+            let (mut min, mut max) = (u64::MAX, 0);
+            let len = sizes[start..]
+                .iter()
+                .take_while(|size| {
+                    (min, max) = (min.min(**size), max.max(**size));
+                    max as f64 <= min as f64 * options.size_tier_ratio
+                })
+                .count();
+
+            (len >= min_runs).then_some(start..start + len)
+        })
+    }
+}
+
+// swaps the compacted files for their outputs and records the run
+fn finish(
+    manifest: &Manifest,
+    stats: &Stats,
+    started: Instant,
+    compacted: &[Arc<Sst>],
+    outputs: Vec<Arc<Sst>>,
+    level: usize,
+) -> Result<()> {
+    let bytes_read = compacted.iter().map(|sst| sst.size()).sum();
+    let bytes_written = outputs.iter().map(|sst| sst.size()).sum();
+
+    manifest.replace(compacted, outputs, level)?;
+    stats.record_compaction(started.elapsed(), bytes_read, bytes_written);
+
+    Ok(())
+}
+
+/// bytes level `level` (at least 1) may hold before it is compacted
+fn level_target_bytes(options: &Options, level: usize) -> u64 {
+    let multiplier = options
+        .level_size_multiplier
+        .saturating_pow(level as u32 - 1);
+
+    options.level_base_bytes.saturating_mul(multiplier)
+}
+
+// smallest and largest key across files
+fn key_range(ssts: &[Arc<Sst>]) -> Option<(Key, Key)> {
+    ssts.iter()
+        .filter_map(|sst| sst.key_range())
+        .fold(None, |range, (first, last)| match range {
+            None => Some((first.clone(), last.clone())),
+            Some((min, max)) => Some((min.min(first.clone()), max.max(last.clone()))),
+        })
+}
+
+fn overlaps(sst: &Sst, range: Option<&(Key, Key)>) -> bool {
+    let (Some((first, last)), Some((start, end))) = (sst.key_range(), range) else {
+        return false;
+    };
+
+    first <= end && start <= last
+}
+
+/// merges files, given oldest first, into new sorted files of about `max_bytes` each,
+/// give or take the versions of the last key
+///
+/// `older` are the files left out of the merge that may hold older versions of its keys.
+/// a key none of them can hold has nothing under its oldest version: merge operands are
+/// resolved into a value and tombstones are dropped.
+/// snapshots are read once the files are picked: any snapshot taken later
+/// reads the newest version of every key in them
+fn merge(
+    manifest: &Manifest,
+    operators: &MergeOperators,
+    snapshots: &Snapshots,
+    options: &Options,
+    ssts: &[Arc<Sst>],
+    older: &[Arc<Sst>],
+    max_bytes: u64,
+) -> Result<Vec<Arc<Sst>>> {
+    let sources = ssts.iter().map(|sst| sst.iter()).collect::<Result<_>>()?;
+
+    let mut outputs = Vec::new();
+    let mut current: Option<(SstWriter, PathBuf)> = None;
+    let now = unix_millis(SystemTime::now());
+
+    for versions in MergeIterator::with_snapshots(sources, operators, snapshots.pinned())? {
+        let mut versions = versions?;
+        let Some(key) = versions.first().map(|entry| entry.key().clone()) else {
+            continue;
+        };
+        let bottommost = older
+            .iter()
+            .all(|sst| !sst.in_range(&key) || !sst.may_contain(&key));
+
+        // with nothing older left, merge operands apply to a missing value
+        if bottommost && let Some(oldest) = versions.pop() {
+            versions.push(operators.resolve(oldest)?);
+        }
+        // an expired put still hides older values, so it stays as a tombstone until then
+        for entry in &mut versions {
+            if entry.is_expired(now) {
+                let seq = entry.seq();
+                *entry = SstEntry::new_delete(entry.key().clone());
+                entry.set_seq(seq);
+            }
+        }
+        // tombstones only stay while there is something left to hide
+        while bottommost && versions.last().is_some_and(SstEntry::is_delete) {
+            versions.pop();
+        }
+
+        if versions.is_empty() {
+            continue;
+        }
+
+        let (writer, _) = match &mut current {
+            Some(current) => current,
+            None => {
+                let path = manifest.next_sst_path();
+                let writer = SstWriter::create(&path, options.bloom_false_positive_rate)?;
+                current.insert((writer, path))
+            }
+        };
+        for entry in &versions {
+            writer.add(entry)?;
+        }
+
+        // files end between keys, so the key ranges of a level stay disjoint
+        if writer.size() >= max_bytes
+            && let Some((writer, path)) = current.take()
+        {
+            writer.finish()?;
+            outputs.push(Arc::new(manifest.open_sst(path)?));
+        }
+    }
+
+    if let Some((writer, path)) = current {
+        writer.finish()?;
+        outputs.push(Arc::new(manifest.open_sst(path)?));
+    }
+
+    Ok(outputs)
+}
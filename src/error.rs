@@ -8,6 +8,18 @@ pub enum KvError {
     Json(#[from] serde_json::Error),
     #[error("Invalid checksum")]
     InvalidChecksum,
+    #[error("Invalid SST: {0}")]
+    InvalidSst(String),
+    #[error("Corrupt WAL: {0}")]
+    CorruptWal(String),
+    #[error("Precondition failed on key {0}")]
+    PreconditionFailed(String),
+    #[error("Transaction conflict on key {0}")]
+    Conflict(String),
+    #[error("Unknown merge operator: {0}")]
+    UnknownMergeOperator(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
 }
 
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -0,0 +1,192 @@
+use arc_swap::ArcSwapOption;
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Result, iterator::MergeIterator, manifest::Manifest, memtable::Table,
+    merge::MergeOperators, options::Options, snapshot::Snapshots, sst::SstWriter, stats::Stats,
+    wal::Wal,
+};
+
+/// a full memtable waiting to be written out, still visible to reads
+pub type ImmutableTable = Arc<Table>;
+
+/// flushes immutable memtables on a background thread, off the write path
+///
+/// holds at most one immutable table, a writer filling the next one
+/// before the previous flush is done waits for the slot to free up
+pub struct Flusher {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    // concurrency safety:
+    // the table in the slot, for readers, who never take the state lock.
+    // only swapped while holding the state lock, along with the slot
+    readable: ArcSwapOption<Table>,
+    state: Mutex<State>,
+    // wakes the worker when a table is scheduled,
+    // and waiting writers when the slot is cleared
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    immutable: Option<ImmutableTable>,
+    // newest wal segment holding entries of the immutable table
+    wal_segment: u64,
+    shutdown: bool,
+}
+
+impl Flusher {
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    pub fn start(
+        manifest: Arc<Manifest>,
+        wal: Arc<Wal>,
+        operators: Arc<MergeOperators>,
+        snapshots: Arc<Snapshots>,
+        options: Options,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            readable: ArcSwapOption::empty(),
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+
+        let worker = shared.clone();
+        let handle = thread::spawn(move || {
+            while let Some((table, wal_segment)) = worker.next_table() {
+                let started = Instant::now();
+
+                // the table stays readable until its file is in the manifest,
+                // and a failed flush is retried so the wal is never dropped early
+                if let Err(e) = flush_table(&manifest, &operators, &snapshots, &options, &table)
+                    .and_then(|_| wal.remove_segments(wal_segment))
+                {
+                    println!("[ERROR] flush: {e}");
+
+                    // the wal still holds the table, it is replayed on the next startup
+                    if worker.is_shutdown() {
+                        break;
+                    }
+
+                    thread::sleep(Self::RETRY_DELAY);
+                    continue;
+                }
+
+                stats.record_flush(started.elapsed());
+                worker.clear();
+            }
+        });
+
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// the table being flushed, if any
+    pub fn immutable(&self) -> Option<ImmutableTable> {
+        self.shared.readable.load_full()
+    }
+
+    /// blocks until the previous table has been flushed, returns whether it had to wait
+    pub fn wait_for_slot(&self) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        let stalled = state.immutable.is_some();
+
+        while state.immutable.is_some() {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+
+        stalled
+    }
+
+    /// hands a full table to the worker, the slot must be free
+    ///
+    /// wal segments up to `wal_segment` are deleted once the table is flushed
+    pub fn schedule(&self, table: ImmutableTable, wal_segment: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        debug_assert!(state.immutable.is_none());
+
+        self.shared.readable.store(Some(table.clone()));
+        state.immutable = Some(table);
+        state.wal_segment = wal_segment;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Shared {
+    // none once shut down with nothing left to flush
+    fn next_table(&self) -> Option<(ImmutableTable, u64)> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(table) = &state.immutable {
+                return Some((table.clone(), state.wal_segment));
+            }
+
+            if state.shutdown {
+                return None;
+            }
+
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.state.lock().unwrap().shutdown
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.immutable = None;
+        self.readable.store(None);
+        drop(state);
+
+        self.changed.notify_all();
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // the worker finishes a pending flush before stopping
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// writes a table to a new sst and records it as the newest file,
+// folding the versions no snapshot reads
+fn flush_table(
+    manifest: &Manifest,
+    operators: &MergeOperators,
+    snapshots: &Snapshots,
+    options: &Options,
+    table: &Arc<Table>,
+) -> Result<()> {
+    let path = manifest.next_sst_path();
+    let mut writer = SstWriter::create(&path, options.bloom_false_positive_rate)?;
+
+    // the table is already in file order, it streams straight into the writer.
+    // tombstones stay, they may hide older versions in other files
+    let source = table.iter_from(None).map(Ok);
+    for versions in MergeIterator::with_snapshots(vec![source], operators, snapshots.pinned())? {
+        for entry in &versions? {
+            writer.add(entry)?;
+        }
+    }
+    writer.finish()?;
+
+    manifest.add(manifest.open_sst(path)?)
+}
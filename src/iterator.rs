@@ -0,0 +1,159 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use crate::{
+    error::Result,
+    memtable::{Key, SstEntry},
+    merge::MergeOperators,
+    snapshot,
+};
+
+/// k-way merge over sorted sources, yielding each key once, with the versions of it
+/// that snapshots still need, newest first
+///
+/// every source holds entries in key order, newest version first. versions that no
+/// snapshot separates collapse into the newer one: it shadows the older one, unless it is
+/// a merge operand that applies to it. an entry seen in more than one source is taken once.
+/// tombstones, and operands with nothing under them, are yielded as-is,
+/// callers decide what to do with them
+pub struct MergeIterator<'a, I> {
+    sources: Vec<I>,
+    heap: BinaryHeap<HeapItem>,
+    operators: &'a MergeOperators,
+    // sequence numbers pinned by snapshots, in order
+    snapshots: Vec<u64>,
+}
+
+impl<'a, I> MergeIterator<'a, I>
+where
+    I: Iterator<Item = Result<SstEntry>>,
+{
+    /// keeps only the newest version of each key
+    pub fn new(sources: Vec<I>, operators: &'a MergeOperators) -> Result<Self> {
+        Self::with_snapshots(sources, operators, Vec::new())
+    }
+
+    /// keeps the versions read by snapshots at the sequence numbers in `snapshots`, in order
+    pub fn with_snapshots(
+        mut sources: Vec<I>,
+        operators: &'a MergeOperators,
+        snapshots: Vec<u64>,
+    ) -> Result<Self> {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+
+        for (source_index, source) in sources.iter_mut().enumerate() {
+            if let Some(entry) = source.next() {
+                heap.push(HeapItem::new(entry?, source_index));
+            }
+        }
+
+        Ok(Self {
+            sources,
+            heap,
+            operators,
+            snapshots,
+        })
+    }
+
+    // the next entry, newest first among those of the same key
+    fn pop(&mut self) -> Result<Option<SstEntry>> {
+        let Some(item) = self.heap.pop() else {
+            return Ok(None);
+        };
+
+        if let Some(entry) = self.sources[item.source_index].next() {
+            self.heap.push(HeapItem::new(entry?, item.source_index));
+        }
+
+        Ok(Some(item.entry))
+    }
+
+    fn next_key(&mut self) -> Result<Option<Vec<SstEntry>>> {
+        let Some(newest) = self.pop()? else {
+            return Ok(None);
+        };
+        let mut versions = vec![newest];
+
+        while let Some(top) = self.heap.peek()
+            && top.key() == versions[0].key()
+        {
+            let older = self.pop()?.expect("heap should have at least one item");
+            let oldest = versions.last_mut().expect("versions are never empty");
+
+            // a copy of an entry already taken
+            if older.seq() >= oldest.seq() {
+                continue;
+            }
+
+            if snapshot::separates(&self.snapshots, older.seq(), oldest.seq()) {
+                versions.push(older);
+            } else if oldest.is_merge() {
+                let newer = versions.pop().expect("versions are never empty");
+                versions.push(self.operators.fold(older, newer)?);
+            }
+        }
+
+        Ok(Some(versions))
+    }
+}
+
+impl<I> Iterator for MergeIterator<'_, I>
+where
+    I: Iterator<Item = Result<SstEntry>>,
+{
+    type Item = Result<Vec<SstEntry>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_key() {
+            Ok(versions) => versions.map(Ok),
+            Err(e) => {
+                // stop after the first error
+                self.heap.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+struct HeapItem {
+    entry: SstEntry,
+    source_index: usize,
+}
+
+impl HeapItem {
+    fn new(entry: SstEntry, source_index: usize) -> Self {
+        Self {
+            entry,
+            source_index,
+        }
+    }
+
+    fn key(&self) -> &Key {
+        self.entry.key()
+    }
+}
+
+// the heap pops the smallest key first, then the newest version of it,
+// then the entry from the newest source
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key()
+            .cmp(other.key())
+            .reverse()
+            .then_with(|| self.entry.seq().cmp(&other.entry.seq()))
+            .then_with(|| self.source_index.cmp(&other.source_index))
+    }
+}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
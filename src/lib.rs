@@ -1,5 +1,22 @@
+pub mod batch;
+pub mod bloom;
+pub mod cache;
+pub mod commit;
+pub mod compaction;
+pub mod crc32c;
 pub mod error;
+pub mod flush;
+pub mod iterator;
+pub mod manifest;
 pub mod memtable;
+pub mod merge;
+pub mod migrate;
+pub mod options;
 pub mod routes;
 pub mod server;
+pub mod shard;
+pub mod snapshot;
+pub mod sst;
+pub mod stats;
+pub mod transaction;
 pub mod wal;
@@ -1,8 +1,13 @@
-use kv::server::Server;
+use kv::{options::Options, server::Server};
 
 #[tokio::main]
 async fn main() {
-    if let Err(e) = Server::run(3000).await {
+    let result = match Options::load() {
+        Ok(options) => Server::run(options).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
         eprintln!("Error: {e}");
         std::process::exit(1);
     }
@@ -0,0 +1,280 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use crate::{cache::BlockCache, error::Result, options::Options, sst::Sst, stats::Stats};
+
+/// files of one level, see [`Manifest`] for their order
+pub type Level = Vec<Arc<Sst>>;
+
+/// the live sst files, grouped by level, and their on-disk listing
+///
+/// level 0 holds flushed files, which may overlap, ordered oldest to newest.
+/// every deeper level holds files with disjoint key ranges, ordered by key,
+/// and is older than the levels above it.
+/// shared between the memtable and the flush and compaction workers
+pub struct Manifest {
+    sst_dir: PathBuf,
+    // concurrency safety:
+    // readers clone the levels and search them without holding the lock,
+    // writers only hold it to swap in new levels
+    levels: RwLock<Vec<Level>>,
+    next_sst_id: AtomicUsize,
+    // newest sequence number ever written to a file, recorded in the manifest file.
+    // compaction drops deleted keys, so the files alone may hold only older ones
+    max_seq: AtomicU64,
+    // concurrency safety:
+    // flushes and compactions both rewrite the manifest file,
+    // this keeps read-modify-write of the list one at a time
+    write_lock: Mutex<()>,
+    // shared by every file opened through `open_sst`
+    block_cache: Arc<BlockCache>,
+}
+
+impl Manifest {
+    pub(crate) const MANIFEST_FILE: &str = "manifest.txt";
+    const TEMP_MANIFEST_FILE: &str = "manifest.tmp";
+    const MAX_SEQ_PREFIX: &str = "max_seq ";
+
+    pub fn new(options: &Options, stats: Arc<Stats>) -> Self {
+        Self {
+            sst_dir: options.sst_dir(),
+            levels: RwLock::new(Vec::new()),
+            next_sst_id: AtomicUsize::new(1),
+            max_seq: AtomicU64::new(0),
+            write_lock: Mutex::new(()),
+            block_cache: Arc::new(BlockCache::new(options.block_cache_bytes, stats)),
+        }
+    }
+
+    pub fn load(&self) -> Result<()> {
+        let manifest_path = self.sst_dir.join(Self::MANIFEST_FILE);
+        fs::create_dir_all(&self.sst_dir)?;
+
+        if !manifest_path.exists() {
+            File::create(&manifest_path)?;
+        }
+
+        // lines are `<level> <file name>`, file names are relative to the sst directory.
+        // order within a level matters: later level 0 files are newer.
+        // a `max_seq <seq>` line holds the newest sequence number ever flushed
+        let manifest = fs::read_to_string(&manifest_path)?;
+        if let Some(max_seq) = manifest
+            .lines()
+            .find_map(|line| line.strip_prefix(Self::MAX_SEQ_PREFIX)?.parse().ok())
+        {
+            self.max_seq.store(max_seq, Ordering::SeqCst);
+        }
+        let manifest_lines: Vec<_> = manifest
+            .lines()
+            .filter(|line| !line.starts_with(Self::MAX_SEQ_PREFIX))
+            .filter_map(|line| {
+                let (level, name) = line.split_once(' ')?;
+
+                Some((level.parse().ok()?, self.sst_dir.join(name)))
+            })
+            .collect();
+        let manifest_set: HashSet<_> = manifest_lines
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect();
+
+        // delete files on disk but not in manifest
+        let manifest_files: HashSet<_> = fs::read_dir(&self.sst_dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                let is_sst_file = path.is_file() && path.extension().unwrap_or_default() == "sst";
+
+                is_sst_file.then_some(path)
+            })
+            .collect();
+
+        for file in manifest_files.difference(&manifest_set) {
+            fs::remove_file(file)?;
+        }
+
+        let max_id = manifest_lines
+            .iter()
+            .filter_map(|(_, path)| Self::sst_id(path))
+            .max()
+            .unwrap_or(0);
+        self.next_sst_id.store(max_id + 1, Ordering::SeqCst);
+
+        // load each file's bloom filter and index
+        let mut levels: Vec<Level> = Vec::new();
+        for (level, path) in manifest_lines {
+            if levels.len() <= level {
+                levels.resize_with(level + 1, Vec::new);
+            }
+
+            levels[level].push(Arc::new(self.open_sst(path)?));
+        }
+        *self.levels.write().unwrap() = levels;
+
+        Ok(())
+    }
+
+    /// snapshot of the live files, oldest first
+    pub fn ssts(&self) -> Vec<Arc<Sst>> {
+        self.levels
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// snapshot of the live files by level, level 0 first
+    pub fn levels(&self) -> Vec<Level> {
+        self.levels.read().unwrap().clone()
+    }
+
+    /// newest sequence number ever written to a file, including ones compacted away since
+    pub fn max_seq(&self) -> u64 {
+        Self::files_max_seq(&self.levels.read().unwrap()).max(self.max_seq.load(Ordering::SeqCst))
+    }
+
+    fn files_max_seq(levels: &[Level]) -> u64 {
+        levels
+            .iter()
+            .flatten()
+            .map(|sst| sst.max_seq())
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn next_sst_path(&self) -> PathBuf {
+        let id = self.next_sst_id.fetch_add(1, Ordering::SeqCst);
+
+        self.sst_dir.join(format!("sst-{id}.sst"))
+    }
+
+    /// opens a file written by a flush or compaction, reading its blocks through the block cache
+    pub fn open_sst(&self, path: PathBuf) -> Result<Sst> {
+        Sst::open(path, self.block_cache.clone())
+    }
+
+    /// records a newly flushed file as the newest one
+    pub fn add(&self, sst: Sst) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+
+        let mut levels = self.levels();
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+        levels[0].push(Arc::new(sst));
+
+        self.persist(&levels)?;
+        *self.levels.write().unwrap() = levels;
+
+        Ok(())
+    }
+
+    /// swaps compacted files, from any level, for their outputs in `level`
+    ///
+    /// outputs may include compacted files, which are then moved rather than rewritten.
+    /// in level 0 the outputs take the place of the oldest compacted file there,
+    /// or go before every other file, so anything flushed while the compaction ran stays newer
+    pub fn replace(
+        &self,
+        compacted: &[Arc<Sst>],
+        outputs: Vec<Arc<Sst>>,
+        level: usize,
+    ) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+
+        let is_compacted = |sst: &Arc<Sst>| compacted.iter().any(|old| Arc::ptr_eq(old, sst));
+
+        let mut levels = self.levels();
+        if levels.len() <= level {
+            levels.resize_with(level + 1, Vec::new);
+        }
+
+        let position = match level {
+            0 => levels[0].iter().position(is_compacted).unwrap_or(0),
+            _ => 0,
+        };
+        for files in &mut levels {
+            files.retain(|sst| !is_compacted(sst));
+        }
+
+        let target = &mut levels[level];
+        let position = position.min(target.len());
+        target.splice(position..position, outputs.iter().cloned());
+        if level > 0 {
+            target.sort_by(|a, b| a.key_range().cmp(&b.key_range()));
+        }
+
+        self.persist(&levels)?;
+        *self.levels.write().unwrap() = levels;
+
+        // files are removed once the last reader drops them
+        for sst in compacted {
+            if !outputs.iter().any(|output| Arc::ptr_eq(output, sst)) {
+                sst.mark_obsolete();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn persist(&self, levels: &[Level]) -> Result<()> {
+        let temp_manifest_path = self.sst_dir.join(Self::TEMP_MANIFEST_FILE);
+        let mut temp_manifest_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&temp_manifest_path)?;
+
+        // write file, the newest sequence number first so a compaction never lowers it
+        let max_seq = Self::files_max_seq(levels).max(self.max_seq.load(Ordering::SeqCst));
+        self.max_seq.store(max_seq, Ordering::SeqCst);
+        let manifest_lines: Vec<_> = std::iter::once(format!("{}{max_seq}", Self::MAX_SEQ_PREFIX))
+            .chain(
+                levels
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(level, files)| files.iter().map(move |sst| (level, sst)))
+                    .filter_map(|(level, sst)| {
+                        let name = sst.path().file_name()?;
+                        Some(format!("{level} {}", name.to_string_lossy()))
+                    }),
+            )
+            .collect();
+        write!(temp_manifest_file, "{}", manifest_lines.join("\n"))?;
+
+        temp_manifest_file.flush()?;
+        temp_manifest_file.sync_all()?;
+
+        // atomic update
+        fs::rename(&temp_manifest_path, self.sst_dir.join(Self::MANIFEST_FILE))?;
+
+        // sync directory, this also persists the entries of new sst files
+        sync_dir(&self.sst_dir)
+    }
+
+    fn sst_id(path: &Path) -> Option<usize> {
+        path.file_stem()?
+            .to_str()?
+            .strip_prefix("sst-")?
+            .parse()
+            .ok()
+    }
+}
+
+pub fn sync_dir(path: &Path) -> Result<()> {
+    let dir = OpenOptions::new().read(true).open(path)?;
+    dir.sync_all()?;
+
+    Ok(())
+}
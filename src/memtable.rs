@@ -1,421 +1,979 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
-use std::{
-    cmp::Ordering,
-    collections::{BinaryHeap, HashSet},
-    fs::{self, File, OpenOptions},
-    io::{BufReader, Write},
-    path::Path,
-};
-
-use crate::error::Result;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+use crossbeam_skiplist::SkipMap;
+
+use crate::batch::{Precondition, WriteBatch};
+use crate::commit::GroupCommit;
+use crate::compaction::Compactor;
+use crate::error::{KvError, Result};
+use crate::flush::Flusher;
+use crate::iterator::MergeIterator;
+use crate::manifest::Manifest;
+use crate::merge::{self, MergeOperand, MergeOperator, MergeOperators};
+use crate::migrate;
+use crate::options::{Options, SyncPolicy, WriteOptions};
+use crate::shard::Sharded;
+use crate::snapshot::{Snapshot, Snapshots};
+use crate::stats::{Stats, StatsSnapshot};
+use crate::transaction::Transaction;
 use crate::wal::Wal;
-use serde::{Deserialize, Serialize};
 
-#[derive(Default)]
+/// the storage engine: memtables, wal and sst files
+///
+/// shared by reference across threads, every method takes `&self` once started
 pub struct MemTable {
-    requests: HashMap<Key, SstEntry>,
     // concurrency safety:
-    // only put requests mutate wal/manifest_cache,
-    // and only one put request (writer) can exist at a time due to the external rw lock on memtable
-    wal: Wal,
-    manifest_cache: Vec<String>,
+    // only the commit leader inserts, and swaps in an empty table on flush.
+    // readers load it without a lock, the table is safe to read while it is written
+    requests: ArcSwap<Table>,
+    // concurrency safety:
+    // writers queue up here, and only the leader of each batch appends to and rotates the wal.
+    // the flush worker only removes sealed wal segments, once their table is in the manifest.
+    // the flush and compaction workers change files in the manifest through its own locks
+    commits: GroupCommit<PendingWrite, u64>,
     // concurrency safety:
-    // only get requests mutate negative_cache,
-    // more than one get request (readers) can exist at a time due to the external rw lock on memtable
-    // so a separate lock is needed here
-    negative_cache: RwLock<HashSet<Key>>,
+    // only the commit leader hands out sequence numbers, readers never need it
+    last_seq: AtomicU64,
     // concurrency safety:
-    // only one update can happen at a time due to the external rw lock on memtable
-    updates_since_compaction: usize,
+    // the leader publishes a group once it is inserted, readers only see published writes,
+    // so they never see part of a group and never wait for one
+    snapshots: Arc<Snapshots>,
+    wal: Arc<Wal>,
+    manifest: Arc<Manifest>,
+    // started once the manifest is loaded, holds the immutable memtable
+    flusher: Option<Flusher>,
+    // concurrency safety:
+    // get requests add misses and the commit leader drops written keys,
+    // a miss is only cached if write_epoch shows no write landed since the lookup began
+    negative_cache: Sharded<HashSet<Key>>,
+    write_epoch: AtomicU64,
+    updates_since_compaction: AtomicUsize,
+    // started once the manifest is loaded
+    compactor: Option<Compactor>,
+    // only changed before startup, shared with the wal replay and the compaction worker
+    merge_operators: Arc<MergeOperators>,
+    options: Options,
+    stats: Arc<Stats>,
 }
 
 impl MemTable {
-    const FLUSH_THRESHOLD: usize = 2000;
-    const COMPACTION_THRESHOLD: usize = 10_000;
-    const MANIFEST_DIR: &str = "data/sst";
-    const MANIFEST_PATH: &str = "data/sst/manifest.txt";
-    const TEMP_MANIFEST_PATH: &str = "data/sst/manifest.tmp";
+    // tombstones written per batch by the expiry sweep
+    const SWEEP_BATCH_SIZE: usize = 1000;
 
-    pub fn startup(&mut self) -> Result<()> {
-        let manifest_path = Path::new(Self::MANIFEST_PATH);
-        fs::create_dir_all(Self::MANIFEST_DIR)?;
+    pub fn new(options: Options) -> Self {
+        let stats = Arc::new(Stats::default());
 
-        if !manifest_path.exists() {
-            File::create(manifest_path)?;
+        Self {
+            requests: ArcSwap::from_pointee(Table::default()),
+            commits: GroupCommit::new(),
+            last_seq: AtomicU64::new(0),
+            snapshots: Arc::new(Snapshots::default()),
+            wal: Arc::new(Wal::new(&options, stats.clone())),
+            manifest: Arc::new(Manifest::new(&options, stats.clone())),
+            flusher: None,
+            negative_cache: Sharded::default(),
+            write_epoch: AtomicU64::new(0),
+            updates_since_compaction: AtomicUsize::new(0),
+            compactor: None,
+            merge_operators: Arc::new(MergeOperators::default()),
+            options,
+            stats,
         }
+    }
+
+    /// makes `operator` available to merge writes as `name`, next to the built-in ones
+    ///
+    /// must be called before startup, and again on every startup for as long as
+    /// operands of the operator may be stored
+    pub fn register_merge_operator(
+        &mut self,
+        name: impl Into<String>,
+        operator: impl MergeOperator + 'static,
+    ) {
+        Arc::get_mut(&mut self.merge_operators)
+            .expect("merge operators are registered before startup")
+            .register(name, operator);
+    }
+
+    pub fn startup(&mut self) -> Result<()> {
+        self.options.validate()?;
 
-        let manifest_lines: HashSet<_> = fs::read_to_string(Self::MANIFEST_PATH)?
-            .lines()
-            .map(|line| line.to_string())
-            .collect();
+        // data written by the json based first version becomes an sst before anything reads it
+        migrate::migrate_json(&self.options, &self.manifest)?;
 
-        // delete files on disk but not in manifest
-        let manifest_files: HashSet<_> = fs::read_dir(Self::MANIFEST_DIR)?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let path = entry.path();
-                let is_sst_file = path.is_file() && path.extension().unwrap_or_default() == "json";
+        // load manifest, including each file's bloom filter and index
+        self.manifest.load()?;
 
-                is_sst_file.then_some(path.to_string_lossy().to_string())
-            })
-            .collect();
+        // replay wal, including any memtable that was not flushed before shutdown
+        let wal_entries = self.wal.startup()?;
 
-        for file in manifest_files.difference(&manifest_lines) {
-            fs::remove_file(file)?;
+        // the flush worker folds these, it could never write the table out otherwise
+        for operand in wal_entries.iter().filter_map(SstEntry::operands).flatten() {
+            self.merge_operators.get(&operand.operator)?;
         }
 
-        // load manifest cache
-        self.manifest_cache.extend(manifest_lines);
+        // sequence numbers carry on from the newest write, wherever it ended up
+        let last_seq = wal_entries
+            .iter()
+            .map(SstEntry::seq)
+            .fold(self.manifest.max_seq(), u64::max);
 
-        // replay wal
-        self.wal.startup()?;
-        let wal_entries = self.wal.existing_entries()?;
-        self.requests.extend(wal_entries);
+        let requests = self.active();
+        for entry in wal_entries {
+            requests.insert(entry);
+        }
+        self.last_seq.store(last_seq, Ordering::SeqCst);
+        self.snapshots.publish(last_seq);
+
+        self.flusher = Some(Flusher::start(
+            self.manifest.clone(),
+            self.wal.clone(),
+            self.merge_operators.clone(),
+            self.snapshots.clone(),
+            self.options.clone(),
+            self.stats.clone(),
+        ));
+        self.compactor = Some(Compactor::start(
+            self.manifest.clone(),
+            self.merge_operators.clone(),
+            self.snapshots.clone(),
+            self.options.clone(),
+            self.stats.clone(),
+        ));
 
         Ok(())
     }
 
-    pub fn put(&mut self, key: Key, value: Value) -> Result<()> {
-        self.wal.put(key.clone(), value)?;
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
 
-        self.requests
-            .insert(key.clone(), SstEntry::new_put(key.clone(), value));
+    pub(crate) fn merge_operators(&self) -> &MergeOperators {
+        &self.merge_operators
+    }
 
-        self.try_flush()?;
-        self.try_compact()?;
-        self.negative_cache.write().unwrap().remove(&key);
+    /// returns the new version of the key, once the write is in the wal and visible to reads
+    pub fn put(&self, key: Key, value: Value) -> Result<u64> {
+        self.put_with(key, value, WriteOptions::default())
+    }
 
-        Ok(())
+    pub fn put_with(&self, key: Key, value: Value, options: WriteOptions) -> Result<u64> {
+        self.write(SstEntry::new_put(key, value).into(), options)
     }
 
     pub fn get(&self, key: &Key) -> Result<Option<Value>> {
-        if let Some(request) = self.requests.get(key) {
-            return Ok(request.value());
-        }
+        Ok(self.get_with_version(key)?.map(|(value, _)| value))
+    }
 
-        if self.search_negative_cache(key) {
-            return Ok(None);
-        }
+    /// value and version of a key
+    pub fn get_with_version(&self, key: &Key) -> Result<Option<(Value, u64)>> {
+        Ok(self.get_entry(key)?.and_then(|entry| match entry {
+            SstEntry::Put(entry) => Some((entry.value, entry.seq)),
+            SstEntry::Delete(_) | SstEntry::Merge(_) => None,
+        }))
+    }
 
-        let result = self.search_sst(key);
-        match result {
-            Ok(None) => {
-                self.negative_cache.write().unwrap().insert(key.clone());
+    /// newest put of a key, with its version and expiry, none if the key is missing,
+    /// deleted or expired
+    pub fn get_entry(&self, key: &Key) -> Result<Option<SstEntry>> {
+        Ok(Self::live(self.lookup(key, None)?))
+    }
 
-                Ok(None)
-            }
-            other => other,
-        }
+    /// point-in-time view of every write visible so far, for `get_at` and `scan_at`
+    ///
+    /// the versions it reads are kept until it is dropped, so it should not be held
+    /// longer than needed
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.latest()
     }
 
-    pub fn delete(&mut self, key: &Key) -> Result<()> {
-        self.wal.delete(key.clone())?;
+    /// starts a transaction reading as of now, see `Transaction`
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self, self.snapshot())
+    }
 
-        self.requests
-            .insert(key.clone(), SstEntry::new_delete(key.clone()));
+    /// value of a key as of `snapshot`
+    pub fn get_at(&self, key: &Key, snapshot: &Snapshot) -> Result<Option<Value>> {
+        Ok(self
+            .get_entry_at(key, snapshot)?
+            .and_then(|entry| entry.value().cloned()))
+    }
 
-        self.try_flush()?;
-        self.try_compact()?;
-        self.negative_cache.write().unwrap().insert(key.clone());
+    /// put of a key as of `snapshot`, like `get_entry`.
+    /// expiry is still checked against the current time
+    pub fn get_entry_at(&self, key: &Key, snapshot: &Snapshot) -> Result<Option<SstEntry>> {
+        Ok(Self::live(self.lookup(key, Some(snapshot))?))
+    }
 
-        Ok(())
+    /// adds `by`, which may be negative, to the integer stored at `key`,
+    /// without reading it first
+    ///
+    /// a missing value, or one that is not an integer, counts as 0, and the result saturates
+    pub fn increment(&self, key: Key, by: i64) -> Result<u64> {
+        self.merge(key, merge::ADD, merge::increment_operand(by))
     }
 
-    fn search_sst(&self, key: &Key) -> Result<Option<Value>> {
-        for sst_path in self.manifest_cache.iter().rev() {
-            let reader = BufReader::new(fs::File::open(sst_path)?);
-            let requests: Vec<SstEntry> = serde_json::from_reader(reader)?;
+    /// applies `operand` to the value at `key` with the merge operator named `operator`,
+    /// without reading it first
+    ///
+    /// fails with `KvError::UnknownMergeOperator` if no such operator is registered
+    pub fn merge(&self, key: Key, operator: &str, operand: Value) -> Result<u64> {
+        self.merge_with(key, operator, operand, WriteOptions::default())
+    }
 
-            if let Some(request) = requests.iter().find(|request| *request.key() == *key) {
-                return Ok(request.value());
-            }
-        }
+    pub fn merge_with(
+        &self,
+        key: Key,
+        operator: &str,
+        operand: Value,
+        options: WriteOptions,
+    ) -> Result<u64> {
+        self.write(SstEntry::new_merge(key, operator, operand).into(), options)
+    }
 
-        Ok(None)
+    pub fn delete(&self, key: &Key) -> Result<u64> {
+        self.delete_with(key, WriteOptions::default())
     }
 
-    fn search_negative_cache(&self, key: &Key) -> bool {
-        self.negative_cache.read().unwrap().contains(key)
+    pub fn delete_with(&self, key: &Key, options: WriteOptions) -> Result<u64> {
+        self.write(SstEntry::new_delete(key.clone()).into(), options)
     }
 
-    fn try_flush(&mut self) -> Result<()> {
-        if self.requests.len() < Self::FLUSH_THRESHOLD {
-            return Ok(());
+    /// writes `value`, or deletes the key if none, only if the key is at version `expected`,
+    /// none meaning missing
+    ///
+    /// returns the sequence number of the write, or none if the key was at another version
+    pub fn compare_and_set(
+        &self,
+        key: Key,
+        expected: Option<u64>,
+        value: Option<Value>,
+    ) -> Result<Option<u64>> {
+        let precondition = match expected {
+            Some(version) => Precondition::VersionIn(vec![version]),
+            None => Precondition::Missing,
+        };
+
+        let mut batch = match value {
+            Some(value) => WriteBatch::from(SstEntry::new_put(key.clone(), value)),
+            None => WriteBatch::from(SstEntry::new_delete(key.clone())),
+        };
+        batch.require(key, precondition);
+
+        match self.write(batch, WriteOptions::default()) {
+            Ok(seq) => Ok(Some(seq)),
+            Err(KvError::PreconditionFailed(_)) => Ok(None),
+            Err(e) => Err(e),
         }
+    }
 
-        let sst_path = format!("data/sst/sst-{}.json", self.next_sst_id()?);
-        let mut sst_file = File::create(&sst_path)?;
+    /// applies every write in `batch` or none of them
+    ///
+    /// entries are numbered in batch order, the sequence number of the last one is returned.
+    /// fails with `KvError::PreconditionFailed` if a precondition of the batch does not hold
+    pub fn write(&self, batch: WriteBatch, options: WriteOptions) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(self.last_seq.load(Ordering::SeqCst));
+        }
 
-        let mut requests: Vec<_> = self.requests.drain().map(|(_, request)| request).collect();
-        requests.sort_by_key(|request| request.key().clone());
+        // operands have to be applicable on every later read
+        for operand in batch
+            .entries()
+            .iter()
+            .filter_map(SstEntry::operands)
+            .flatten()
+        {
+            self.merge_operators.get(&operand.operator)?;
+        }
 
-        serde_json::to_writer(&sst_file, &requests)?;
-        sst_file.flush()?;
-        sst_file.sync_all()?;
+        let write = PendingWrite {
+            batch,
+            sync_policy: options.sync_policy.unwrap_or(self.options.sync_policy),
+        };
 
-        let mut temp_manifest_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(Self::TEMP_MANIFEST_PATH)?;
+        self.commits.submit(write, |writes| self.commit(writes))
+    }
 
-        let manifest_lines = fs::read_to_string(Self::MANIFEST_PATH)?;
-        if manifest_lines.is_empty() {
-            write!(temp_manifest_file, "{sst_path}")?;
-        } else {
-            write!(temp_manifest_file, "{manifest_lines}\n{sst_path}")?;
+    /// merged view of the memtable and every sst over `[start, end)`, in key order,
+    /// as of when the scan starts
+    ///
+    /// newer entries shadow older ones and deleted keys are skipped
+    pub fn scan(
+        &self,
+        start: Option<&Key>,
+        end: Option<&Key>,
+    ) -> Result<impl Iterator<Item = Result<(Key, Value)>> + use<'_>> {
+        self.scan_at(&self.snapshot(), start, end)
+    }
+
+    /// merged view over `[start, end)` as of `snapshot`, see `scan`
+    ///
+    /// the snapshot is only needed until this returns
+    pub fn scan_at<'a>(
+        &'a self,
+        snapshot: &Snapshot,
+        start: Option<&Key>,
+        end: Option<&Key>,
+    ) -> Result<impl Iterator<Item = Result<(Key, Value)>> + use<'a>> {
+        let seq = snapshot.seq();
+        // memtables are read lazily from `start` on: versions inserted meanwhile are
+        // newer than the snapshot, and a table is never changed once it is flushed
+        let visible = |table: Arc<Table>| {
+            table
+                .iter_from(start)
+                .filter(move |entry| entry.seq() <= seq)
+                .map(Ok)
+        };
+
+        // the active table is taken before the immutable one, and both before the file list:
+        // a flush in between then shows up twice, rather than not at all
+        let requests = self.active();
+        let immutable = self.flusher.as_ref().and_then(Flusher::immutable);
+
+        // sources go oldest to newest, so the memtables are last
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<SstEntry>> + 'a>> = Vec::new();
+
+        for sst in &self.manifest.ssts() {
+            if !sst.overlaps(start, end) {
+                continue;
+            }
+
+            let entries = match start {
+                Some(start) => sst.iter_from(start)?,
+                None => sst.iter()?,
+            };
+            sources.push(Box::new(entries.filter(move |entry| {
+                entry.as_ref().map_or(true, |entry| entry.seq() <= seq)
+            })));
         }
-        temp_manifest_file.flush()?;
-        temp_manifest_file.sync_all()?;
 
-        fs::rename(Self::TEMP_MANIFEST_PATH, Self::MANIFEST_PATH)?;
+        if let Some(immutable) = immutable {
+            sources.push(Box::new(visible(immutable)));
+        }
 
-        let sst_dir = OpenOptions::new().read(true).open("data/sst")?;
-        sst_dir.sync_all()?;
+        sources.push(Box::new(visible(requests)));
 
-        self.wal.reset()?;
+        let end = end.cloned();
+        let now = unix_millis(SystemTime::now());
 
-        self.manifest_cache.push(sst_path);
+        Ok(MergeIterator::new(sources, &self.merge_operators)?
+            // without snapshots to keep versions for, each key comes with its newest only
+            .map(|versions| versions.map(|mut versions| versions.swap_remove(0)))
+            .take_while(move |entry| match entry {
+                Ok(entry) => end.as_ref().is_none_or(|end| entry.key() < end),
+                Err(_) => true,
+            })
+            // every source is merged, so operands left over have nothing under them
+            .filter_map(move |entry| {
+                match entry.and_then(|entry| self.merge_operators.resolve(entry)) {
+                    Ok(entry) if entry.is_expired(now) => None,
+                    Ok(entry) => entry
+                        .value()
+                        .map(|value| Ok((entry.key().clone(), value.clone()))),
+                    Err(e) => Some(Err(e)),
+                }
+            }))
+    }
 
-        Ok(())
+    /// keys starting with `prefix`, in key order, resuming at `start` if given
+    pub fn scan_prefix(
+        &self,
+        prefix: &str,
+        start: Option<&Key>,
+    ) -> Result<impl Iterator<Item = Result<(Key, Value)>> + use<'_>> {
+        let prefix_start = prefix.to_string();
+        let start = start
+            .filter(|start| **start > prefix_start)
+            .unwrap_or(&prefix_start);
+
+        // every key in [prefix, prefix_end) starts with prefix
+        self.scan(Some(start), prefix_end(prefix).as_ref())
     }
 
-    fn try_compact(&mut self) -> Result<()> {
-        self.updates_since_compaction += 1;
+    pub fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.scan_prefix(prefix, None)?
+            .try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
 
-        if self.updates_since_compaction < Self::COMPACTION_THRESHOLD {
-            return Ok(());
+    /// writes a tombstone for every expired put in the files, unless the key was written since
+    ///
+    /// expired puts already read as missing, the tombstones let the values under them be
+    /// compacted away without waiting for compaction to reach the file. returns the number
+    /// of tombstones written
+    pub fn sweep_expired(&self) -> Result<u64> {
+        let now = unix_millis(SystemTime::now());
+        let mut swept = 0;
+        let mut expired = Vec::new();
+
+        for sst in self.manifest.ssts() {
+            if sst
+                .min_expires_at()
+                .is_none_or(|expires_at| expires_at > now)
+            {
+                continue;
+            }
+
+            for entry in sst.iter()? {
+                let entry = entry?;
+                if !entry.is_expired(now) {
+                    continue;
+                }
+
+                // older versions, and puts swept before but not compacted yet, are skipped
+                let newest = self.lookup(entry.key(), None)?;
+                if newest.is_none_or(|newest| newest.seq() != entry.seq()) {
+                    continue;
+                }
+
+                expired.push(entry);
+                if expired.len() == Self::SWEEP_BATCH_SIZE {
+                    swept += self.write_tombstones(mem::take(&mut expired))?;
+                }
+            }
         }
+        swept += self.write_tombstones(expired)?;
 
-        self.compact_sst()?;
-        self.updates_since_compaction = 0;
+        self.stats.record_expired_swept(swept);
 
-        Ok(())
+        Ok(swept)
     }
 
-    fn compact_sst(&mut self) -> Result<()> {
-        if self.manifest_cache.is_empty() {
-            return Ok(());
+    // writes a tombstone over each put in one batch, for those still the newest write of their key.
+    // a batch is rejected as a whole, so a key written meanwhile is left out and the rest retried
+    fn write_tombstones(&self, mut expired: Vec<SstEntry>) -> Result<u64> {
+        while !expired.is_empty() {
+            let mut batch = WriteBatch::new();
+            for entry in &expired {
+                batch.delete(entry.key().clone());
+                batch.require(entry.key().clone(), Precondition::LastWrite(entry.seq()));
+            }
+
+            match self.write(batch, WriteOptions::default()) {
+                Ok(_) => return Ok(expired.len() as u64),
+                Err(KvError::PreconditionFailed(key)) => {
+                    expired.retain(|entry| *entry.key() != key);
+                }
+                Err(e) => return Err(e),
+            }
         }
 
-        let lsm_tree: Vec<Vec<SstEntry>> = self
-            .manifest_cache
-            .iter()
-            .map(|sst_path| -> Result<Vec<SstEntry>> {
-                let reader = BufReader::new(fs::File::open(sst_path)?);
-                let sst: Vec<SstEntry> = serde_json::from_reader(reader)?;
+        Ok(0)
+    }
 
-                Ok(sst)
-            })
-            .collect::<Result<_>>()?;
+    // a looked up entry if it is a live put.
+    // lookups resolve merge operands, so anything but a put means missing
+    fn live(entry: Option<SstEntry>) -> Option<SstEntry> {
+        let now = unix_millis(SystemTime::now());
 
-        let mut heap: BinaryHeap<_> = lsm_tree
-            .iter()
-            .enumerate()
-            .filter_map(|(sst_file_index, requests)| {
-                requests
-                    .first()
-                    .map(|request| HeapItem::new(request.key().clone(), sst_file_index, 0))
-            })
-            .collect();
+        entry.filter(|entry| entry.value().is_some() && !entry.is_expired(now))
+    }
 
-        let mut new_sst_paths = Vec::new();
-        let mut current_entries: Vec<SstEntry> = Vec::new();
-        let mut next_id = self.next_sst_id()?;
+    // newest entry of a key as of `snapshot`, or of now if none, tombstones included,
+    // with merge operands folded into their value
+    fn lookup(&self, key: &Key, snapshot: Option<&Snapshot>) -> Result<Option<SstEntry>> {
+        let epoch = self.write_epoch.load(Ordering::SeqCst);
+        let latest = snapshot.is_none();
+        // a read of the latest value is a snapshot too, so a compaction
+        // running meanwhile cannot fold away versions under it
+        let pinned;
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                pinned = self.snapshot();
+                &pinned
+            }
+        };
 
-        while let Some(item) = heap.pop() {
-            let key = item.key.clone();
-            let mut drained = vec![item];
+        // versions are folded newest first, each one older than the one before
+        let mut seq = snapshot.seq();
+        let mut found = None;
 
-            while let Some(top) = heap.peek()
-                && top.key == key
-            {
-                drained.push(heap.pop().expect("heap should have at least one item"));
+        let requests = self.active().versions(key);
+        let immutable = self.search_immutable(key);
+        for request in requests.into_iter().chain(immutable) {
+            if self.fold_under(&mut found, &mut seq, request)? {
+                return Ok(found);
             }
+        }
 
-            let newest_entry = &lsm_tree[drained[0].sst_file_index][drained[0].sst_position_index];
+        // the cache only knows about the latest state, and not what is under an operand
+        if latest && found.is_none() && self.search_negative_cache(key) {
+            return Ok(None);
+        }
 
-            // keep newest non-delete entry
-            if !newest_entry.is_delete() {
-                current_entries.push(newest_entry.clone());
+        let found = self.search_sst(key, found, seq)?;
+        if latest && found.is_none() {
+            self.cache_miss(key, epoch);
+        }
 
-                if current_entries.len() >= Self::FLUSH_THRESHOLD {
-                    let sst_path = Self::sst_path(next_id);
-                    next_id += 1;
+        found
+            .map(|found| self.merge_operators.resolve(found))
+            .transpose()
+    }
 
-                    self.write_sst(&sst_path, &current_entries)?;
+    // continues a lookup through the files, newest first, until the value is complete
+    fn search_sst(
+        &self,
+        key: &Key,
+        mut found: Option<SstEntry>,
+        mut seq: u64,
+    ) -> Result<Option<SstEntry>> {
+        for sst in self.manifest.ssts().iter().rev() {
+            if !sst.in_range(key) {
+                continue;
+            }
 
-                    new_sst_paths.push(sst_path);
-                    current_entries.clear();
-                }
+            if !sst.may_contain(key) {
+                self.stats.record_bloom_miss();
+                continue;
             }
 
-            for HeapItem {
-                key: _,
-                sst_file_index,
-                sst_position_index,
-            } in drained
-            {
-                let next_index = sst_position_index + 1;
+            self.stats.record_bloom_hit();
 
-                if let Some(next) = lsm_tree[sst_file_index].get(next_index) {
-                    let item = HeapItem::new(next.key().clone(), sst_file_index, next_index);
+            let requests = sst.versions(key, seq)?;
+            if requests.is_empty() {
+                self.stats.record_bloom_false_positive();
+                continue;
+            }
 
-                    heap.push(item);
+            for request in requests {
+                if self.fold_under(&mut found, &mut seq, request)? {
+                    return Ok(found);
                 }
             }
         }
 
-        if !current_entries.is_empty() {
-            let sst_path = Self::sst_path(next_id);
-            self.write_sst(&sst_path, &current_entries)?;
-            new_sst_paths.push(sst_path);
+        Ok(found)
+    }
+
+    // folds an older entry under the newer ones found so far, true once they make a whole value.
+    // entries newer than `seq` are skipped, a copy of one already folded included
+    fn fold_under(
+        &self,
+        found: &mut Option<SstEntry>,
+        seq: &mut u64,
+        older: SstEntry,
+    ) -> Result<bool> {
+        if older.seq() > *seq {
+            return Ok(false);
         }
+        *seq = older.seq().saturating_sub(1);
 
-        self.update_manifest(&new_sst_paths)?;
+        let entry = match found.take() {
+            Some(newer) => self.merge_operators.fold(older, newer)?,
+            None => older,
+        };
+        let complete = !entry.is_merge();
+        *found = Some(entry);
 
-        for old_sst_file in &self.manifest_cache {
-            fs::remove_file(old_sst_file)?;
-        }
+        Ok(complete)
+    }
 
-        self.manifest_cache = new_sst_paths;
+    // the table taking writes
+    fn active(&self) -> Arc<Table> {
+        self.requests.load_full()
+    }
 
-        Ok(())
+    // versions of a key in the table being flushed, newest first
+    fn search_immutable(&self, key: &Key) -> Vec<SstEntry> {
+        self.flusher
+            .as_ref()
+            .and_then(Flusher::immutable)
+            .map(|table| table.versions(key))
+            .unwrap_or_default()
     }
 
-    fn sst_path(id: usize) -> String {
-        format!("data/sst/sst-{id}.json")
+    fn search_negative_cache(&self, key: &Key) -> bool {
+        self.negative_cache.shard(key).read().unwrap().contains(key)
     }
 
-    fn write_sst(&self, sst_path: &str, entries: &[SstEntry]) -> Result<()> {
-        let mut sst_file = File::create(sst_path)?;
+    fn cache_miss(&self, key: &Key, epoch: u64) {
+        let mut negative_cache = self.negative_cache.shard(key).write().unwrap();
 
-        // write file
-        serde_json::to_writer(&sst_file, entries)?;
-        sst_file.flush()?;
-        sst_file.sync_all()?;
+        // a write since the lookup began may have added the key
+        if self.write_epoch.load(Ordering::SeqCst) == epoch {
+            negative_cache.insert(key.clone());
+        }
+    }
 
-        // sync directory
-        let sst_dir = OpenOptions::new().read(true).open(Self::MANIFEST_DIR)?;
-        sst_dir.sync_all()?;
+    // runs on the commit leader only, one group at a time.
+    // writes whose preconditions fail are left out of the group
+    fn commit(&self, writes: Vec<PendingWrite>) -> Vec<Result<u64>> {
+        let mut results = Vec::with_capacity(writes.len());
+        let mut accepted = Vec::new();
+        // entries written by earlier writes of the group, which lookups do not see yet
+        let mut written = HashMap::new();
+        let mut last_seq = self.last_seq.load(Ordering::SeqCst);
+
+        for mut write in writes {
+            if let Err(e) = self.check_preconditions(&write.batch, &written) {
+                results.push(Err(e));
+                continue;
+            }
+
+            write.batch.set_seqs(last_seq + 1);
+            last_seq += write.batch.len() as u64;
+
+            for entry in write.batch.entries() {
+                written.insert(entry.key().clone(), entry.clone());
+            }
+            results.push(Ok(last_seq));
+            accepted.push(write);
+        }
+
+        // numbers are never handed out twice, even if the group fails
+        self.last_seq.store(last_seq, Ordering::SeqCst);
+
+        if accepted.is_empty() {
+            return results;
+        }
+
+        let updates = accepted.iter().map(|write| write.batch.len()).sum();
+        if let Err(e) = self.apply(accepted) {
+            let message = e.to_string();
+            for result in &mut results {
+                if result.is_ok() {
+                    *result = Err(std::io::Error::other(message.clone()).into());
+                }
+            }
+
+            return results;
+        }
+
+        // the group is logged and visible, failing it now would have clients retry writes
+        // that already happened. the memtable stays full, so the next group tries again
+        if let Err(e) = self.try_flush() {
+            println!("[ERROR] scheduling flush: {e}");
+        }
+        self.try_compact(updates);
+
+        results
+    }
+
+    fn check_preconditions(
+        &self,
+        batch: &WriteBatch,
+        written: &HashMap<Key, SstEntry>,
+    ) -> Result<()> {
+        for (key, precondition) in batch.preconditions() {
+            let latest = match written.get(key) {
+                Some(entry) => Some(entry.clone()),
+                None => self.lookup(key, None)?,
+            };
+
+            if !precondition.holds(latest.as_ref()) {
+                return Err(KvError::PreconditionFailed(key.clone()));
+            }
+        }
 
         Ok(())
     }
 
-    fn update_manifest(&self, new_sst_paths: &[String]) -> Result<()> {
-        let mut temp_manifest_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(Self::TEMP_MANIFEST_PATH)?;
+    // logs and applies a group of numbered writes, the commit point of each of them
+    fn apply(&self, writes: Vec<PendingWrite>) -> Result<()> {
+        // the group shares one fsync, so it gets the strongest policy asked for
+        let sync_policy = writes
+            .iter()
+            .map(|write| write.sync_policy)
+            .max()
+            .unwrap_or(SyncPolicy::None);
+        let batches: Vec<_> = writes.into_iter().map(|write| write.batch).collect();
+        let entries = || batches.iter().flat_map(WriteBatch::entries);
+
+        self.wal.append(&batches, sync_policy)?;
+        self.stats.record_wal_batch(batches.len());
+
+        // published as a whole once inserted, so reads never see part of a batch
+        let requests = self.active();
+        for entry in entries() {
+            requests.insert(entry.clone());
+        }
+        self.snapshots
+            .publish(entries().map(SstEntry::seq).max().unwrap_or(0));
+
+        // after the insert, so a concurrent miss either sees the key or skips the cache
+        self.write_epoch.fetch_add(1, Ordering::SeqCst);
+        for entry in entries() {
+            let mut negative_cache = self.negative_cache.shard(entry.key()).write().unwrap();
+
+            if entry.is_delete() {
+                negative_cache.insert(entry.key().clone());
+            } else {
+                negative_cache.remove(entry.key());
+            }
+        }
+
+        Ok(())
+    }
 
-        // write file
-        if !new_sst_paths.is_empty() {
-            let manifest_lines = new_sst_paths.join("\n");
-            write!(temp_manifest_file, "{manifest_lines}")?;
+    // moves the full memtable to the immutable slot and leaves the sst write to the flush worker
+    fn try_flush(&self) -> Result<()> {
+        if self.active().size() < self.options.memtable_size_bytes {
+            return Ok(());
         }
 
-        temp_manifest_file.flush()?;
-        temp_manifest_file.sync_all()?;
+        let Some(flusher) = &self.flusher else {
+            return Ok(());
+        };
 
-        // atomic update
-        fs::rename(Self::TEMP_MANIFEST_PATH, Self::MANIFEST_PATH)?;
+        // only stalls when writes outpace the previous flush.
+        // the leader is the only one scheduling, so the slot stays free once it is
+        if flusher.wait_for_slot() {
+            self.stats.record_flush_stall();
+        }
 
-        // sync directory
-        let sst_dir = OpenOptions::new().read(true).open(Self::MANIFEST_DIR)?;
-        sst_dir.sync_all()?;
+        // readers look here before the slot, so the table goes in the slot before it is
+        // replaced: they see its entries in one or both, never in neither
+        let wal_segment = self.wal.rotate()?;
+        flusher.schedule(self.active(), wal_segment);
+        self.requests.store(Arc::new(Table::default()));
 
         Ok(())
     }
 
-    fn next_sst_id(&self) -> Result<usize> {
-        let last_id = self
-            .manifest_cache
-            .last()
-            .and_then(|line| {
-                line.trim_start_matches("data/sst/sst-")
-                    .trim_end_matches(".json")
-                    .parse()
-                    .ok()
-            })
-            .unwrap_or(0);
+    fn try_compact(&self, updates: usize) {
+        let updates = self
+            .updates_since_compaction
+            .fetch_add(updates, Ordering::Relaxed)
+            + updates;
 
-        Ok(last_id + 1)
+        if updates < self.options.compaction_threshold {
+            return;
+        }
+
+        if let Some(compactor) = &self.compactor {
+            compactor.trigger();
+        }
+        self.updates_since_compaction.store(0, Ordering::Relaxed);
     }
 }
 
-pub type Key = String;
-pub type Value = u32;
-
-#[derive(Clone)]
-pub enum SstEntry {
-    Put(PutEntry),
-    Delete(DeleteEntry),
+// a write waiting in the commit queue
+struct PendingWrite {
+    batch: WriteBatch,
+    sync_policy: SyncPolicy,
 }
 
-impl Serialize for SstEntry {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        match self {
-            Self::Put(entry) => entry.serialize(serializer),
-            Self::Delete(entry) => entry.serialize(serializer),
+/// smallest key greater than every key starting with `prefix`, none if unbounded
+fn prefix_end(prefix: &str) -> Option<Key> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+
+    while let Some(last) = chars.pop() {
+        // skip over the surrogate range, which has no chars
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
         }
     }
+
+    None
 }
 
-impl<'de> Deserialize<'de> for SstEntry {
-    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        #[serde(deny_unknown_fields)]
-        struct RawEntry {
-            key: Key,
-            value: Option<Value>,
-            deleted: Option<bool>,
-        }
+pub type Key = String;
+pub type Value = Vec<u8>;
 
-        let entry = RawEntry::deserialize(deserializer)?;
+/// every version of every key written to a memtable, in key order and newest first
+///
+/// versions are folded on reads and on flush rather than on insert, so a snapshot
+/// taken while a group is being inserted never loses the version it reads
+#[derive(Default)]
+pub struct Table {
+    // concurrency safety:
+    // a lock-free skiplist, only the commit leader inserts while any thread reads
+    entries: SkipMap<(Key, Reverse<u64>), SstEntry>,
+    size: AtomicU64,
+}
 
-        if let Some(value) = entry.value {
-            return Ok(SstEntry::new_put(entry.key, value));
-        }
+impl Table {
+    /// adds a version newer than any of the key so far
+    pub fn insert(&self, entry: SstEntry) {
+        // the key is held twice, in the skiplist and in the entry
+        let size = entry.key().len() + entry.size();
+        self.size.fetch_add(size as u64, Ordering::SeqCst);
 
-        if let Some(deleted) = entry.deleted
-            && deleted
-        {
-            return Ok(SstEntry::new_delete(entry.key));
+        self.entries
+            .insert((entry.key().clone(), Reverse(entry.seq())), entry);
+    }
+
+    /// versions of a key, newest first
+    pub fn versions(&self, key: &Key) -> Vec<SstEntry> {
+        let newest = (key.clone(), Reverse(u64::MAX));
+        let oldest = (key.clone(), Reverse(0));
+
+        self.entries
+            .range(newest..=oldest)
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    /// versions from the first key at or after `start` on, in key order and newest first
+    pub fn iter_from(self: &Arc<Self>, start: Option<&Key>) -> TableIter {
+        TableIter {
+            table: self.clone(),
+            next: match start {
+                Some(start) => Bound::Included((start.clone(), Reverse(u64::MAX))),
+                None => Bound::Unbounded,
+            },
         }
+    }
 
-        Err(serde::de::Error::custom(
-            "entry must have either value or deleted: true",
-        ))
+    /// approximate bytes held by the versions
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
     }
 }
 
+/// versions of a table in key order, see `Table::iter_from`
+///
+/// owns the table, and seeks past the last version it returned on every step,
+/// so versions inserted meanwhile show up if they sort after it
+pub struct TableIter {
+    table: Arc<Table>,
+    next: Bound<(Key, Reverse<u64>)>,
+}
+
+impl Iterator for TableIter {
+    type Item = SstEntry;
+
+    fn next(&mut self) -> Option<SstEntry> {
+        let entry = self.table.entries.lower_bound(self.next.as_ref())?;
+        self.next = Bound::Excluded(entry.key().clone());
+
+        Some(entry.value().clone())
+    }
+}
+
+/// a put, a tombstone or a merge operand, with the sequence number of the write that made it
+///
+/// sequence numbers are assigned on commit, in commit order, and double as the
+/// version of the key
+#[derive(Clone)]
+pub enum SstEntry {
+    Put(PutEntry),
+    Delete(DeleteEntry),
+    Merge(MergeEntry),
+}
+
 impl SstEntry {
     pub fn new_put(key: Key, value: Value) -> Self {
-        Self::Put(PutEntry { key, value })
+        Self::Put(PutEntry {
+            key,
+            value,
+            seq: 0,
+            expires_at: None,
+        })
+    }
+
+    /// a put that reads as missing from `expires_at` on, in unix milliseconds
+    pub fn new_expiring_put(key: Key, value: Value, expires_at: u64) -> Self {
+        Self::Put(PutEntry {
+            key,
+            value,
+            seq: 0,
+            expires_at: Some(expires_at),
+        })
     }
 
     pub fn new_delete(key: Key) -> Self {
-        Self::Delete(DeleteEntry { key, deleted: true })
+        Self::Delete(DeleteEntry { key, seq: 0 })
+    }
+
+    pub fn new_merge(key: Key, operator: impl Into<String>, operand: Value) -> Self {
+        Self::from_operands(
+            key,
+            VecDeque::from([MergeOperand {
+                operator: operator.into(),
+                value: operand,
+            }]),
+        )
+    }
+
+    pub(crate) fn from_operands(key: Key, operands: VecDeque<MergeOperand>) -> Self {
+        Self::Merge(MergeEntry {
+            key,
+            operands,
+            seq: 0,
+        })
     }
 
     pub fn key(&self) -> &Key {
         match self {
             Self::Put(entry) => &entry.key,
             Self::Delete(entry) => &entry.key,
+            Self::Merge(entry) => &entry.key,
+        }
+    }
+
+    /// value of a put
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Self::Put(entry) => Some(&entry.value),
+            Self::Delete(_) | Self::Merge(_) => None,
+        }
+    }
+
+    /// operands of a merge, oldest first, still to be applied to the value under it
+    pub fn operands(&self) -> Option<&VecDeque<MergeOperand>> {
+        match self {
+            Self::Merge(entry) => Some(&entry.operands),
+            Self::Put(_) | Self::Delete(_) => None,
+        }
+    }
+
+    // operands of a merge, taken out of it
+    pub(crate) fn into_operands(self) -> Option<VecDeque<MergeOperand>> {
+        match self {
+            Self::Merge(entry) => Some(entry.operands),
+            Self::Put(_) | Self::Delete(_) => None,
+        }
+    }
+
+    /// sequence number, 0 until committed
+    pub fn seq(&self) -> u64 {
+        match self {
+            Self::Put(entry) => entry.seq,
+            Self::Delete(entry) => entry.seq,
+            Self::Merge(entry) => entry.seq,
+        }
+    }
+
+    pub(crate) fn set_seq(&mut self, seq: u64) {
+        match self {
+            Self::Put(entry) => entry.seq = seq,
+            Self::Delete(entry) => entry.seq = seq,
+            Self::Merge(entry) => entry.seq = seq,
         }
     }
 
-    pub fn value(&self) -> Option<Value> {
+    /// expiry of a put, in unix milliseconds
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Self::Put(entry) => entry.expires_at,
+            Self::Delete(_) | Self::Merge(_) => None,
+        }
+    }
+
+    /// whether this is a put that expired at or before `now`, in unix milliseconds
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// version of the key after this entry, none once it is deleted or expired
+    pub fn version(&self) -> Option<u64> {
         match self {
-            Self::Put(entry) => Some(entry.value),
+            Self::Put(_) if self.is_expired(unix_millis(SystemTime::now())) => None,
+            Self::Put(entry) => Some(entry.seq),
+            Self::Merge(entry) => Some(entry.seq),
             Self::Delete(_) => None,
         }
     }
@@ -423,49 +981,54 @@ impl SstEntry {
     pub fn is_delete(&self) -> bool {
         matches!(self, Self::Delete(_))
     }
+
+    pub fn is_merge(&self) -> bool {
+        matches!(self, Self::Merge(_))
+    }
+
+    /// approximate bytes the entry takes in memory
+    pub fn size(&self) -> usize {
+        let data = match self {
+            Self::Put(entry) => entry.key.len() + entry.value.len(),
+            Self::Delete(entry) => entry.key.len(),
+            Self::Merge(entry) => {
+                entry.key.len()
+                    + entry
+                        .operands
+                        .iter()
+                        .map(|operand| operand.operator.len() + operand.value.len())
+                        .sum::<usize>()
+            }
+        };
+
+        mem::size_of::<Self>() + data
+    }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone)]
 pub struct PutEntry {
     key: Key,
     value: Value,
+    seq: u64,
+    expires_at: Option<u64>,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone)]
 pub struct DeleteEntry {
     key: Key,
-    deleted: bool,
+    seq: u64,
 }
 
-#[derive(Eq, PartialEq)]
-struct HeapItem {
+#[derive(Clone)]
+pub struct MergeEntry {
     key: Key,
-    sst_file_index: usize,
-    sst_position_index: usize,
+    // reads and compactions fold versions newest first, so older operands join at the front
+    operands: VecDeque<MergeOperand>,
+    seq: u64,
 }
 
-impl HeapItem {
-    fn new(key: Key, sst_file_index: usize, sst_position_index: usize) -> Self {
-        Self {
-            key,
-            sst_file_index,
-            sst_position_index,
-        }
-    }
-}
-
-impl Ord for HeapItem {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match self.key.cmp(&other.key) {
-            Ordering::Equal => self.sst_file_index.cmp(&other.sst_file_index),
-            ordering => ordering.reverse(),
-        }
-        .then_with(|| self.sst_position_index.cmp(&other.sst_position_index))
-    }
-}
-
-impl PartialOrd for HeapItem {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// `time` in milliseconds since the unix epoch, the unit of expiry times
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
 }
@@ -0,0 +1,267 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use serde_json::Value as Json;
+
+use crate::{
+    error::{KvError, Result},
+    memtable::{Key, SstEntry, Value},
+};
+
+// merge operands are stored as entries of their own and folded into the value they apply to
+// lazily: when a read or a compaction comes across them together, or when a read reaches the
+// oldest entry of a key without finding a value, in which case they apply to a missing one.
+//
+// each operand names the operator that applies it, so a key may mix operators.
+// operands with nothing under them yet are kept in order, and adjacent operands of the same
+// operator are combined into one where the operator allows it
+
+pub const ADD: &str = "add";
+pub const APPEND: &str = "append";
+pub const MAX: &str = "max";
+pub const SET_UNION: &str = "set_union";
+
+/// read-modify-write applied by the engine, without the client reading the value first
+///
+/// operators must be deterministic: an operand may be applied on any read,
+/// and again after a restart, so it has to give the same result every time
+pub trait MergeOperator: Send + Sync {
+    /// applies `operand` to the value stored at `key`, none if it is missing
+    fn merge(&self, key: &Key, existing: Option<&[u8]>, operand: &[u8]) -> Value;
+
+    /// a single operand with the effect of `older` then `newer` on any value,
+    /// none if there is no such operand
+    fn combine(&self, _key: &Key, _older: &[u8], _newer: &[u8]) -> Option<Value> {
+        None
+    }
+}
+
+/// an operand waiting for the value under it
+#[derive(Clone)]
+pub struct MergeOperand {
+    pub operator: String,
+    pub value: Value,
+}
+
+/// merge operators by name, the built-in ones are always there
+#[derive(Clone)]
+pub struct MergeOperators {
+    operators: HashMap<String, Arc<dyn MergeOperator>>,
+}
+
+impl Default for MergeOperators {
+    fn default() -> Self {
+        let mut operators = Self {
+            operators: HashMap::new(),
+        };
+        operators.register(ADD, Add);
+        operators.register(APPEND, Append);
+        operators.register(MAX, Max);
+        operators.register(SET_UNION, SetUnion);
+
+        operators
+    }
+}
+
+impl MergeOperators {
+    /// adds an operator, or replaces the one with the same name
+    pub fn register(&mut self, name: impl Into<String>, operator: impl MergeOperator + 'static) {
+        self.operators.insert(name.into(), Arc::new(operator));
+    }
+
+    pub fn get(&self, name: &str) -> Result<&dyn MergeOperator> {
+        self.operators
+            .get(name)
+            .map(|operator| operator.as_ref())
+            .ok_or_else(|| KvError::UnknownMergeOperator(name.to_string()))
+    }
+
+    /// stacks `newer` on top of `older`, both entries of the same key
+    ///
+    /// puts and tombstones replace what is under them, merge operands apply to it.
+    /// the result is still a merge operand if `older` is one.
+    /// merging into an expiring put keeps its expiry, whether it has passed or not,
+    /// so the result does not depend on when the fold happens
+    pub fn fold(&self, older: SstEntry, newer: SstEntry) -> Result<SstEntry> {
+        if !newer.is_merge() {
+            return Ok(newer);
+        }
+        let key = newer.key().clone();
+        let seq = newer.seq();
+        let mut operands = newer.into_operands().expect("newer is a merge");
+
+        let mut entry = match older {
+            SstEntry::Merge(_) => {
+                // the newer operands are the ones piling up as versions are folded newest first,
+                // so the older ones are slotted in front of them rather than copied under
+                let older_operands = older.into_operands().expect("older is a merge");
+                for operand in older_operands.into_iter().rev() {
+                    self.push_front(&key, &mut operands, operand)?;
+                }
+
+                SstEntry::from_operands(key, operands)
+            }
+            older => {
+                let mut value = older.value().cloned();
+                for operand in operands {
+                    value = Some(self.get(&operand.operator)?.merge(
+                        &key,
+                        value.as_deref(),
+                        &operand.value,
+                    ));
+                }
+
+                // the result expires with the value it was merged into
+                let value = value.unwrap_or_default();
+                match older.expires_at() {
+                    Some(expires_at) => SstEntry::new_expiring_put(key, value, expires_at),
+                    None => SstEntry::new_put(key, value),
+                }
+            }
+        };
+        entry.set_seq(seq);
+
+        Ok(entry)
+    }
+
+    /// an entry with nothing older under it, merge operands apply to a missing value
+    pub fn resolve(&self, entry: SstEntry) -> Result<SstEntry> {
+        match entry.is_merge() {
+            true => self.fold(SstEntry::new_delete(entry.key().clone()), entry),
+            false => Ok(entry),
+        }
+    }
+
+    // puts an older operand in front, combining it with the first one if they share an operator
+    fn push_front(
+        &self,
+        key: &Key,
+        operands: &mut VecDeque<MergeOperand>,
+        operand: MergeOperand,
+    ) -> Result<()> {
+        if let Some(first) = operands.front_mut()
+            && first.operator == operand.operator
+            && let Some(value) =
+                self.get(&operand.operator)?
+                    .combine(key, &operand.value, &first.value)
+        {
+            first.value = value;
+            return Ok(());
+        }
+
+        operands.push_front(operand);
+
+        Ok(())
+    }
+}
+
+/// operand of `add` adding `by` to a counter
+pub fn increment_operand(by: i64) -> Value {
+    by.to_string().into_bytes()
+}
+
+/// adds json integers, saturating at the i64 bounds.
+/// a missing value, or one that is not an integer, counts as 0
+///
+/// deltas are not combined ahead of the value: saturation depends on the order they
+/// are added in, so `10 + MAX - 5` has to read the same whenever it is folded
+pub struct Add;
+
+impl MergeOperator for Add {
+    fn merge(&self, _key: &Key, existing: Option<&[u8]>, operand: &[u8]) -> Value {
+        increment_operand(counter(existing).saturating_add(counter(Some(operand))))
+    }
+}
+
+fn counter(bytes: Option<&[u8]>) -> i64 {
+    bytes
+        .and_then(|bytes| std::str::from_utf8(bytes).ok())
+        .and_then(|text| text.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// appends the json operand to a json list.
+/// a missing value, or one that is not a list, counts as empty
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn merge(&self, _key: &Key, existing: Option<&[u8]>, operand: &[u8]) -> Value {
+        let mut list = json_list(existing);
+        list.push(json(operand));
+
+        to_bytes(Json::Array(list))
+    }
+}
+
+/// keeps the larger json number, a value that is not a number loses to any operand
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn merge(&self, _key: &Key, existing: Option<&[u8]>, operand: &[u8]) -> Value {
+        let number = |bytes: &[u8]| match json(bytes) {
+            Json::Number(number) => Some(number),
+            _ => None,
+        };
+
+        match (existing.and_then(number), number(operand)) {
+            (Some(existing), Some(operand)) => {
+                let larger = match (existing.as_i64(), operand.as_i64()) {
+                    (Some(existing), Some(operand)) => operand > existing,
+                    _ => operand.as_f64() > existing.as_f64(),
+                };
+
+                match larger {
+                    true => operand.to_string().into_bytes(),
+                    false => existing.to_string().into_bytes(),
+                }
+            }
+            (Some(existing), None) => existing.to_string().into_bytes(),
+            (None, _) => operand.to_vec(),
+        }
+    }
+
+    fn combine(&self, key: &Key, older: &[u8], newer: &[u8]) -> Option<Value> {
+        Some(self.merge(key, Some(older), newer))
+    }
+}
+
+/// adds the items of the json list operand to a json list, skipping those already in it.
+/// a missing value, or one that is not a list, counts as empty
+pub struct SetUnion;
+
+impl MergeOperator for SetUnion {
+    fn merge(&self, _key: &Key, existing: Option<&[u8]>, operand: &[u8]) -> Value {
+        let mut set = json_list(existing);
+
+        for item in json_list(Some(operand)) {
+            if !set.contains(&item) {
+                set.push(item);
+            }
+        }
+
+        to_bytes(Json::Array(set))
+    }
+
+    fn combine(&self, key: &Key, older: &[u8], newer: &[u8]) -> Option<Value> {
+        Some(self.merge(key, Some(older), newer))
+    }
+}
+
+// values that are not json are taken as a json string of their bytes
+fn json(bytes: &[u8]) -> Json {
+    serde_json::from_slice(bytes)
+        .unwrap_or_else(|_| Json::String(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+fn json_list(bytes: Option<&[u8]>) -> Vec<Json> {
+    match bytes.map(json) {
+        Some(Json::Array(list)) => list,
+        _ => Vec::new(),
+    }
+}
+
+fn to_bytes(value: Json) -> Value {
+    serde_json::to_vec(&value).expect("json values always serialize")
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{KvError, Result},
+    manifest::{Manifest, sync_dir},
+    memtable::{Key, SstEntry},
+    options::Options,
+    sst::SstWriter,
+};
+
+const JSON_WAL_FILE: &str = "wal.db";
+
+/// converts a data directory written by the first version of the store, which kept
+/// json sst files (`sst/sst-N.json`, listed oldest first in the manifest by path)
+/// and a json lines log (`wal/wal.db`)
+///
+/// the newest value of every key is written to a single sst file, which replaces the json
+/// files in the manifest. values were json numbers, they become the bytes of their json text.
+/// the old files are only deleted once the manifest is replaced, so a crash part way through
+/// leaves them to be converted again on the next startup.
+/// runs before the manifest is loaded, and does nothing for a directory written since
+pub fn migrate_json(options: &Options, manifest: &Manifest) -> Result<()> {
+    let sst_dir = options.sst_dir();
+    let manifest_path = sst_dir.join(Manifest::MANIFEST_FILE);
+    let wal_path = options.wal_dir().join(JSON_WAL_FILE);
+
+    let listing = match fs::read_to_string(&manifest_path) {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let listed: Vec<_> = listing.lines().filter(|line| !line.is_empty()).collect();
+
+    // a converted manifest lists binary files, the json files left are only stragglers
+    let converted = listed.iter().any(|line| !line.ends_with(".json"));
+    if !converted && (!listed.is_empty() || wal_path.exists()) {
+        println!(
+            "[INFO] migrate: converting json data in {}",
+            options.data_dir.display()
+        );
+
+        let mut values = BTreeMap::new();
+        for line in &listed {
+            let name = Path::new(line)
+                .file_name()
+                .ok_or_else(|| KvError::InvalidSst(format!("bad manifest line {line}")))?;
+            read_json_sst(&sst_dir.join(name), &mut values)?;
+        }
+        if wal_path.exists() {
+            read_json_wal(&wal_path, &mut values)?;
+        }
+
+        match write_sst(manifest, options, values)? {
+            Some(path) => manifest.add(manifest.open_sst(path)?)?,
+            // nothing is left, which is how an empty store starts out
+            None => {
+                File::create(&manifest_path)?.sync_all()?;
+                sync_dir(&sst_dir)?;
+            }
+        }
+    }
+
+    remove_json_files(&sst_dir, &wal_path)
+}
+
+// the newest value of each key, none once it is deleted
+type Values = BTreeMap<Key, Option<u32>>;
+
+// applies a json sst file, a sorted array of `{"key", "value"}` or `{"key", "deleted": true}`
+fn read_json_sst(path: &Path, values: &mut Values) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    let entries: Vec<JsonSstEntry> = serde_json::from_reader(reader)?;
+
+    for entry in entries {
+        let value = match (entry.value, entry.deleted) {
+            (Some(value), _) => Some(value),
+            (None, Some(true)) => None,
+            _ => {
+                return Err(KvError::InvalidSst(format!(
+                    "{} entry for {} has neither a value nor deleted: true",
+                    path.display(),
+                    entry.key
+                )));
+            }
+        };
+        values.insert(entry.key, value);
+    }
+
+    Ok(())
+}
+
+// applies the json lines log, which was synced line by line:
+// a bad last line is a write cut short by a crash, a bad line before it is corruption
+fn read_json_wal(path: &Path, values: &mut Values) -> Result<()> {
+    let lines = BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    for (number, line) in lines.iter().enumerate() {
+        let entry = match JsonLog::validate(line) {
+            Ok(entry) => entry,
+            Err(_) if number + 1 == lines.len() => break,
+            Err(e) => {
+                return Err(KvError::CorruptWal(format!(
+                    "{} line {}: {e}",
+                    path.display(),
+                    number + 1
+                )));
+            }
+        };
+
+        match entry {
+            JsonEntry::Put { key, value } => values.insert(key, Some(value)),
+            JsonEntry::Delete { key } => values.insert(key, None),
+        };
+    }
+
+    Ok(())
+}
+
+// writes the live values, numbered in key order, none if there are none
+fn write_sst(manifest: &Manifest, options: &Options, values: Values) -> Result<Option<PathBuf>> {
+    let mut live = values
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .peekable();
+    if live.peek().is_none() {
+        return Ok(None);
+    }
+
+    let path = manifest.next_sst_path();
+    let mut writer = SstWriter::create(&path, options.bloom_false_positive_rate)?;
+    for (seq, (key, value)) in (1..).zip(live) {
+        let mut entry = SstEntry::new_put(key, value.to_string().into_bytes());
+        entry.set_seq(seq);
+        writer.add(&entry)?;
+    }
+    writer.finish()?;
+
+    Ok(Some(path))
+}
+
+fn remove_json_files(sst_dir: &Path, wal_path: &Path) -> Result<()> {
+    if wal_path.exists() {
+        fs::remove_file(wal_path)?;
+    }
+
+    let Ok(entries) = fs::read_dir(sst_dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+
+        if path.is_file() && path.extension().unwrap_or_default() == "json" {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonSstEntry {
+    key: Key,
+    value: Option<u32>,
+    deleted: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct JsonLog {
+    hash: u32,
+    entry: JsonEntry,
+}
+
+impl JsonLog {
+    // the entry of a line, if its checksum matches
+    fn validate(line: &str) -> Result<JsonEntry> {
+        let JsonLog { hash, entry } = serde_json::from_str(line.trim_end())?;
+
+        // the checksum covers the entry as the first version serialized it
+        if crc32fast::hash(serde_json::to_string(&entry)?.as_bytes()) != hash {
+            return Err(KvError::InvalidChecksum);
+        }
+
+        Ok(entry)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum JsonEntry {
+    Put { key: Key, value: u32 },
+    Delete { key: Key },
+}
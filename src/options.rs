@@ -0,0 +1,302 @@
+use std::{fs, path::PathBuf, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::error::{KvError, Result};
+
+/// engine and server configuration
+///
+/// sources are applied in order, each overriding the previous one:
+/// defaults, the toml file given by `--config` or `KV_CONFIG`,
+/// `KV_*` environment variables, then `--*` command line flags.
+/// e.g. `memtable_size_bytes` is `KV_MEMTABLE_SIZE_BYTES` or `--memtable-size-bytes`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// wal and sst files live under this directory
+    pub data_dir: PathBuf,
+    pub bind_address: String,
+    /// approximate memtable bytes that trigger a flush to a new sst,
+    /// also the size of the files compaction writes
+    pub memtable_size_bytes: u64,
+    /// number of writes between compaction checks
+    pub compaction_threshold: usize,
+    /// how compaction picks files, fixed for the lifetime of the engine
+    pub compaction_style: CompactionStyle,
+    /// number of level 0 files that triggers a compaction into level 1
+    pub level0_file_limit: usize,
+    /// bytes level 1 may hold before it is compacted into level 2
+    pub level_base_bytes: u64,
+    /// growth of the byte target from one level to the next
+    pub level_size_multiplier: u64,
+    /// number of levels, including level 0
+    pub max_levels: usize,
+    /// number of similarly sized runs merged together by size-tiered compaction
+    pub size_tier_min_runs: usize,
+    /// largest size ratio between two runs of the same tier
+    pub size_tier_ratio: f64,
+    /// false positive rate targeted by the bloom filters of newly written ssts
+    pub bloom_false_positive_rate: f64,
+    /// bytes of decoded sst blocks kept in memory, 0 to read every block from disk
+    pub block_cache_bytes: u64,
+    /// default for writes that do not pick their own, see [`WriteOptions`]
+    pub sync_policy: SyncPolicy,
+    /// longest a write may wait for its fsync under `SyncPolicy::Interval`
+    pub sync_interval_ms: u64,
+    /// unsynced bytes that force an fsync under `SyncPolicy::Interval`, without waiting for the timer
+    pub sync_interval_bytes: u64,
+    /// skip the rest of a wal segment after a corrupt record instead of refusing to start,
+    /// losing the writes in it
+    pub salvage_wal: bool,
+    /// how often expired entries in ssts are turned into tombstones, 0 to never sweep
+    pub expiry_sweep_interval_ms: u64,
+}
+
+/// per-write overrides of [`Options`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    pub sync_policy: Option<SyncPolicy>,
+}
+
+/// when wal writes are forced to disk, ordered from weakest to strongest
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// leave it to the os, a crash can lose recent writes
+    None,
+    /// fsync on a background timer, a crash can lose the last `sync_interval_ms` of writes
+    Interval,
+    /// fsync before acknowledging the write
+    Always,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(Self::None),
+            "interval" => Ok(Self::Interval),
+            "always" => Ok(Self::Always),
+            _ => Err(KvError::Config(format!("invalid sync policy: {value}"))),
+        }
+    }
+}
+
+/// see the strategies in [`crate::compaction`]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionStyle {
+    /// non-overlapping levels with growing size targets, the default
+    Leveled,
+    /// merges runs of similar size, less rewriting for write-heavy workloads
+    SizeTiered,
+    /// merges every file into a single run
+    Full,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("data"),
+            bind_address: "127.0.0.1:3000".to_string(),
+            memtable_size_bytes: 4 * 1024 * 1024,
+            compaction_threshold: 10_000,
+            compaction_style: CompactionStyle::Leveled,
+            level0_file_limit: 4,
+            level_base_bytes: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            max_levels: 7,
+            size_tier_min_runs: 4,
+            size_tier_ratio: 2.0,
+            bloom_false_positive_rate: 0.01,
+            block_cache_bytes: 8 * 1024 * 1024,
+            sync_policy: SyncPolicy::Always,
+            sync_interval_ms: 100,
+            sync_interval_bytes: 1024 * 1024,
+            salvage_wal: false,
+            expiry_sweep_interval_ms: 60_000,
+        }
+    }
+}
+
+impl Options {
+    const ENV_PREFIX: &str = "KV_";
+    const NAMES: [&str; 19] = [
+        "config",
+        "data_dir",
+        "bind_address",
+        "memtable_size_bytes",
+        "compaction_threshold",
+        "compaction_style",
+        "level0_file_limit",
+        "level_base_bytes",
+        "level_size_multiplier",
+        "max_levels",
+        "size_tier_min_runs",
+        "size_tier_ratio",
+        "bloom_false_positive_rate",
+        "block_cache_bytes",
+        "sync_policy",
+        "sync_interval_ms",
+        "sync_interval_bytes",
+        "salvage_wal",
+        "expiry_sweep_interval_ms",
+    ];
+
+    /// loads options from the process environment and command line
+    pub fn load() -> Result<Self> {
+        Self::from_sources(std::env::vars(), std::env::args().skip(1))
+    }
+
+    pub fn from_sources(
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self> {
+        let vars: Vec<_> = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                // other KV_* variables are not ours to reject
+                let name = name.strip_prefix(Self::ENV_PREFIX)?.to_lowercase();
+                Self::NAMES
+                    .contains(&name.as_str())
+                    .then_some((name, value))
+            })
+            .collect();
+        let flags = Self::parse_flags(args)?;
+
+        let config_path = flags
+            .iter()
+            .chain(&vars)
+            .find(|(name, _)| name == "config")
+            .map(|(_, path)| path.clone());
+
+        let mut options = match config_path {
+            Some(path) => toml::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| KvError::Config(format!("{path}: {e}")))?,
+            None => Self::default(),
+        };
+
+        for (name, value) in vars.iter().chain(&flags) {
+            if name != "config" {
+                options.set(name, value)?;
+            }
+        }
+
+        options.validate()?;
+
+        Ok(options)
+    }
+
+    /// rejects values the engine cannot run with
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |name: &str, reason: &str| {
+            Err(KvError::Config(format!(
+                "invalid value for {name}: {reason}"
+            )))
+        };
+
+        if self.memtable_size_bytes == 0 {
+            return invalid("memtable_size_bytes", "must be above 0");
+        }
+        if self.level0_file_limit == 0 {
+            return invalid("level0_file_limit", "must be above 0");
+        }
+        if self.max_levels < 2 {
+            return invalid("max_levels", "must be at least 2");
+        }
+        if self.size_tier_min_runs < 2 {
+            return invalid("size_tier_min_runs", "must be at least 2");
+        }
+        // nan included
+        if self.size_tier_ratio.is_nan() || self.size_tier_ratio < 1.0 {
+            return invalid("size_tier_ratio", "must be at least 1");
+        }
+        if !(0.0 < self.bloom_false_positive_rate && self.bloom_false_positive_rate < 1.0) {
+            return invalid("bloom_false_positive_rate", "must be between 0 and 1");
+        }
+        // the wal syncer would spin on a zero interval
+        if self.sync_interval_ms == 0 {
+            return invalid("sync_interval_ms", "must be above 0");
+        }
+
+        Ok(())
+    }
+
+    pub fn sst_dir(&self) -> PathBuf {
+        self.data_dir.join("sst")
+    }
+
+    pub fn wal_dir(&self) -> PathBuf {
+        self.data_dir.join("wal")
+    }
+
+    // `--some-flag value` or `--some-flag=value` into ("some_flag", "value")
+    fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Vec<(String, String)>> {
+        let mut args = args.into_iter();
+        let mut flags = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(KvError::Config(format!("unexpected argument: {arg}")));
+            };
+
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| KvError::Config(format!("missing value for --{flag}")))?;
+                    (flag.to_string(), value)
+                }
+            };
+
+            flags.push((name.replace('-', "_"), value));
+        }
+
+        Ok(flags)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T> {
+            value
+                .parse()
+                .map_err(|_| KvError::Config(format!("invalid value for {name}: {value}")))
+        }
+
+        match name {
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "bind_address" => self.bind_address = value.to_string(),
+            "memtable_size_bytes" => self.memtable_size_bytes = parse(name, value)?,
+            "compaction_threshold" => self.compaction_threshold = parse(name, value)?,
+            "compaction_style" => {
+                self.compaction_style = match value {
+                    "leveled" => CompactionStyle::Leveled,
+                    "size_tiered" => CompactionStyle::SizeTiered,
+                    "full" => CompactionStyle::Full,
+                    _ => {
+                        return Err(KvError::Config(format!(
+                            "invalid value for {name}: {value}"
+                        )));
+                    }
+                }
+            }
+            "level0_file_limit" => self.level0_file_limit = parse(name, value)?,
+            "level_base_bytes" => self.level_base_bytes = parse(name, value)?,
+            "level_size_multiplier" => self.level_size_multiplier = parse(name, value)?,
+            "max_levels" => self.max_levels = parse(name, value)?,
+            "size_tier_min_runs" => self.size_tier_min_runs = parse(name, value)?,
+            "size_tier_ratio" => self.size_tier_ratio = parse(name, value)?,
+            "bloom_false_positive_rate" => self.bloom_false_positive_rate = parse(name, value)?,
+            "block_cache_bytes" => self.block_cache_bytes = parse(name, value)?,
+            "sync_policy" => self.sync_policy = parse(name, value)?,
+            "sync_interval_ms" => self.sync_interval_ms = parse(name, value)?,
+            "sync_interval_bytes" => self.sync_interval_bytes = parse(name, value)?,
+            "salvage_wal" => self.salvage_wal = parse(name, value)?,
+            "expiry_sweep_interval_ms" => self.expiry_sweep_interval_ms = parse(name, value)?,
+            _ => return Err(KvError::Config(format!("unknown option: {name}"))),
+        }
+
+        Ok(())
+    }
+}
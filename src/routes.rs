@@ -1,21 +1,163 @@
-use crate::memtable::{Key, Value};
+use crate::batch::{Precondition, WriteBatch};
+use crate::error::{KvError, Result};
+use crate::memtable::{Key, MemTable, SstEntry, Value, unix_millis};
+use crate::merge;
+use crate::options::WriteOptions;
 use crate::server::AppState;
 use axum::{
     Json,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+        header::{ACCEPT, CONTENT_TYPE, ETAG, EXPIRES, IF_MATCH, IF_NONE_MATCH},
+    },
+    response::{IntoResponse, Response},
 };
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const JSON: &str = "application/json";
+// per-request sync policy: `always`, `interval` or `none`
+const DURABILITY: &str = "x-kv-durability";
+const OCTET_STREAM: &str = "application/octet-stream";
+// keys starting with this are taken by the server's own endpoints, `/_stats` and the like
+const RESERVED_PREFIX: &str = "_";
+// latest expiry an http date can carry, the end of year 9999 in unix milliseconds
+const MAX_EXPIRES_AT: u64 = 253_402_300_799_000;
 
 #[derive(Deserialize)]
 pub struct PutKeyRequest {
-    value: Value,
+    value: serde_json::Value,
+    // the key reads as missing this many seconds from now
+    ttl_seconds: Option<u64>,
 }
 
+/// stored bytes as json: values written as json round-trip as-is,
+/// anything else is returned base64 encoded
 #[derive(Serialize)]
 pub struct ValueResponse {
-    pub value: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_base64: Option<String>,
+}
+
+impl ValueResponse {
+    pub fn new(value: &[u8]) -> Self {
+        match serde_json::from_slice(value) {
+            Ok(value) => Self {
+                value: Some(value),
+                value_base64: None,
+            },
+            Err(_) => Self {
+                value: None,
+                value_base64: Some(BASE64.encode(value)),
+            },
+        }
+    }
+}
+
+/// one write of a `POST /_batch` body, which is a json list of them
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Put {
+        key: Key,
+        value: serde_json::Value,
+        ttl_seconds: Option<u64>,
+    },
+    Delete {
+        key: Key,
+    },
+    Merge {
+        key: Key,
+        operator: String,
+        value: serde_json::Value,
+    },
+}
+
+impl BatchOperation {
+    // the entry written, a reserved key, a value that does not serialize, or an expiry past
+    // what an http date can carry, is a bad request
+    fn into_entry(self) -> std::result::Result<SstEntry, StatusCode> {
+        let to_vec = |value| serde_json::to_vec(&value).map_err(|_| StatusCode::BAD_REQUEST);
+
+        let entry = match self {
+            Self::Put {
+                key,
+                value,
+                ttl_seconds: Some(ttl_seconds),
+            } => SstEntry::new_expiring_put(key, to_vec(value)?, expires_in(ttl_seconds)?),
+            Self::Put { key, value, .. } => SstEntry::new_put(key, to_vec(value)?),
+            Self::Delete { key } => SstEntry::new_delete(key),
+            Self::Merge {
+                key,
+                operator,
+                value,
+            } => SstEntry::new_merge(key, operator, to_vec(value)?),
+        };
+        check_key(entry.key())?;
+
+        Ok(entry)
+    }
+}
+
+/// body of `POST /_txn`
+#[derive(Deserialize)]
+pub struct TransactionRequest {
+    #[serde(default)]
+    reads: Vec<ReadPrecondition>,
+    #[serde(default)]
+    writes: Vec<BatchOperation>,
+}
+
+/// a key the writes of a transaction depend on, with the version it was read at,
+/// none if it was missing
+#[derive(Deserialize)]
+pub struct ReadPrecondition {
+    key: Key,
+    version: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct IncrementQuery {
+    by: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ScanQuery {
+    start: Option<Key>,
+    end: Option<Key>,
+    limit: Option<usize>,
+    // `next_cursor` from the previous page, takes precedence over `start`
+    cursor: Option<Key>,
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    limit: Option<usize>,
+    cursor: Option<Key>,
+}
+
+#[derive(Serialize)]
+pub struct KeyValue {
+    pub key: Key,
+    #[serde(flatten)]
+    pub value: ValueResponse,
+}
+
+#[derive(Serialize)]
+pub struct ScanResponse {
+    pub entries: Vec<KeyValue>,
+    pub next_cursor: Option<Key>,
+}
+
+#[derive(Serialize)]
+pub struct CountResponse {
+    pub prefix: String,
+    pub count: usize,
 }
 
 #[derive(Serialize)]
@@ -23,22 +165,74 @@ pub struct ErrorResponse {
     pub error: String,
 }
 
+/// stores the raw body for `application/octet-stream`,
+/// otherwise the body must be json of the form `{"value": ..., "ttl_seconds": ...}`
+///
+/// the key expires after `ttl_seconds` if given, or at the `Expires` header date if given.
+/// honors `If-Match` and `If-None-Match`, and returns the new version as the `ETag`
 pub async fn put_key(
     Path(key): Path<Key>,
     State(state): State<AppState>,
-    Json(payload): Json<PutKeyRequest>,
-) -> StatusCode {
-    match state.buckets().write().unwrap().put(key, payload.value) {
-        Ok(_) => StatusCode::OK,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let (value, ttl_seconds) = match request_value(&headers, &body) {
+        Ok(value) => value,
+        Err(status) => return status.into_response(),
+    };
+    let options = match write_options(&headers) {
+        Ok(options) => options,
+        Err(status) => return status.into_response(),
+    };
+    let expires_at = match ttl_seconds.map(expires_in).transpose() {
+        Ok(Some(expires_at)) => Some(expires_at),
+        Ok(None) => match header_expiry(&headers) {
+            Ok(expires_at) => expires_at,
+            Err(status) => return status.into_response(),
+        },
+        Err(status) => return status.into_response(),
+    };
+
+    let entry = match expires_at {
+        Some(expires_at) => SstEntry::new_expiring_put(key, value, expires_at),
+        None => SstEntry::new_put(key, value),
+    };
+    let batch = match conditional_batch(&headers, entry) {
+        Ok(batch) => batch,
+        Err(status) => return status.into_response(),
+    };
+
+    match blocking(state, move |buckets| buckets.write(batch, options)).await {
+        Ok(version) => (StatusCode::OK, [(ETAG, etag(version))]).into_response(),
+        Err(KvError::PreconditionFailed(_)) => StatusCode::PRECONDITION_FAILED.into_response(),
         Err(e) => {
             println!("[ERROR] put: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
-pub async fn get_key(Path(key): Path<Key>, State(state): State<AppState>) -> impl IntoResponse {
-    let Ok(value) = state.buckets().read().unwrap().get(&key) else {
+/// returns the raw bytes if `Accept` prefers `application/octet-stream`, json otherwise
+pub async fn get_key(
+    Path(key): Path<Key>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(raw) = prefers_raw(&headers) else {
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            Json(ErrorResponse {
+                error: format!("Accept must allow {JSON} or {OCTET_STREAM}"),
+            }),
+        )
+            .into_response();
+    };
+
+    let lookup = {
+        let key = key.clone();
+        blocking(state, move |buckets| buckets.get_entry(&key)).await
+    };
+    let Ok(entry) = lookup else {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(ErrorResponse {
@@ -48,8 +242,31 @@ pub async fn get_key(Path(key): Path<Key>, State(state): State<AppState>) -> imp
             .into_response();
     };
 
-    match value {
-        Some(value) => (StatusCode::OK, Json(ValueResponse { value })).into_response(),
+    let mut response_headers = HeaderMap::new();
+    if let Some(entry) = &entry {
+        response_headers.insert(ETAG, etag(entry.seq()));
+    }
+    if let Some(expires_at) = entry.as_ref().and_then(SstEntry::expires_at) {
+        // expiries written through the library are not bounded by the date format
+        let expires_at = UNIX_EPOCH + Duration::from_millis(expires_at.min(MAX_EXPIRES_AT));
+        response_headers.insert(
+            EXPIRES,
+            HeaderValue::from_str(&httpdate::fmt_http_date(expires_at))
+                .expect("http dates are valid header values"),
+        );
+    }
+
+    match entry.as_ref().and_then(SstEntry::value) {
+        Some(value) if raw => {
+            response_headers.insert(CONTENT_TYPE, HeaderValue::from_static(OCTET_STREAM));
+            (StatusCode::OK, response_headers, value.clone()).into_response()
+        }
+        Some(value) => (
+            StatusCode::OK,
+            response_headers,
+            Json(ValueResponse::new(value)),
+        )
+            .into_response(),
         None => (
             StatusCode::NOT_FOUND,
             Json(ErrorResponse {
@@ -60,9 +277,455 @@ pub async fn get_key(Path(key): Path<Key>, State(state): State<AppState>) -> imp
     }
 }
 
-pub async fn delete_key(Path(key): Path<Key>, State(state): State<AppState>) -> StatusCode {
-    match state.buckets().write().unwrap().delete(&key) {
+// runs a call that may block on the blocking pool, so it does not hold up the runtime:
+// writes wait for their group commit, which lets concurrent writers share one,
+// and reads and scans go through files on disk
+async fn blocking<T: Send + 'static>(
+    state: AppState,
+    call: impl FnOnce(&MemTable) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(move || call(state.buckets()))
+        .await
+        .map_err(|e| KvError::from(std::io::Error::other(e)))?
+}
+
+// strong entity tag of a version
+fn etag(version: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{version}\""))
+        .expect("quoted numbers are valid header values")
+}
+
+// unix milliseconds `ttl_seconds` from now, a bad request past what an http date can carry
+fn expires_in(ttl_seconds: u64) -> std::result::Result<u64, StatusCode> {
+    ttl_seconds
+        .checked_mul(1000)
+        .and_then(|ttl| unix_millis(SystemTime::now()).checked_add(ttl))
+        .filter(|expires_at| *expires_at <= MAX_EXPIRES_AT)
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+// the `Expires` header in unix milliseconds,
+// a date that does not parse, or that an http date cannot carry, is a bad request
+fn header_expiry(headers: &HeaderMap) -> std::result::Result<Option<u64>, StatusCode> {
+    headers
+        .get(EXPIRES)
+        .map(|expires| {
+            expires
+                .to_str()
+                .ok()
+                .and_then(|expires| httpdate::parse_http_date(expires).ok())
+                .map(unix_millis)
+                .filter(|expires_at| *expires_at <= MAX_EXPIRES_AT)
+                .ok_or(StatusCode::BAD_REQUEST)
+        })
+        .transpose()
+}
+
+// a key starting with `RESERVED_PREFIX` cannot be written, a bad request
+fn check_key(key: &Key) -> std::result::Result<(), StatusCode> {
+    match key.starts_with(RESERVED_PREFIX) {
+        true => Err(StatusCode::BAD_REQUEST),
+        false => Ok(()),
+    }
+}
+
+// a single write, conditional on the `If-Match` and `If-None-Match` headers,
+// a reserved key is a bad request
+fn conditional_batch(
+    headers: &HeaderMap,
+    entry: SstEntry,
+) -> std::result::Result<WriteBatch, StatusCode> {
+    check_key(entry.key())?;
+    let key = entry.key().clone();
+    let mut batch = WriteBatch::from(entry);
+
+    for name in [IF_MATCH, IF_NONE_MATCH] {
+        if let Some(precondition) = header_precondition(headers, name) {
+            batch.require(key.clone(), precondition);
+        }
+    }
+
+    Ok(batch)
+}
+
+// precondition of an `If-Match` or `If-None-Match` header, none if it is absent.
+// conditional writes compare strongly, so weak tags, or tags that are not versions, never match
+fn header_precondition(headers: &HeaderMap, name: HeaderName) -> Option<Precondition> {
+    let if_match = name == IF_MATCH;
+    let mut values = headers.get_all(name).iter().peekable();
+    values.peek()?;
+
+    let mut versions = Vec::new();
+    for value in values {
+        for tag in value.to_str().unwrap_or_default().split(',').map(str::trim) {
+            if tag == "*" {
+                return Some(match if_match {
+                    true => Precondition::Exists,
+                    false => Precondition::Missing,
+                });
+            }
+
+            if let Some(version) = tag
+                .strip_prefix('"')
+                .and_then(|tag| tag.strip_suffix('"'))
+                .and_then(|tag| tag.parse().ok())
+            {
+                versions.push(version);
+            }
+        }
+    }
+
+    Some(match if_match {
+        true => Precondition::VersionIn(versions),
+        false => Precondition::VersionNotIn(versions),
+    })
+}
+
+fn write_options(headers: &HeaderMap) -> std::result::Result<WriteOptions, StatusCode> {
+    let sync_policy = headers
+        .get(DURABILITY)
+        .map(|durability| {
+            durability
+                .to_str()
+                .ok()
+                .and_then(|durability| durability.trim().parse().ok())
+                .ok_or(StatusCode::BAD_REQUEST)
+        })
+        .transpose()?;
+
+    Ok(WriteOptions { sync_policy })
+}
+
+// the value, and `ttl_seconds` of a json body
+fn request_value(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> std::result::Result<(Value, Option<u64>), StatusCode> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(media_type)
+        .unwrap_or(JSON);
+
+    match content_type {
+        OCTET_STREAM => Ok((body.to_vec(), None)),
+        JSON => {
+            let payload: PutKeyRequest =
+                serde_json::from_slice(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+            let value = serde_json::to_vec(&payload.value).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+            Ok((value, payload.ttl_seconds))
+        }
+        _ => Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    }
+}
+
+// the first acceptable media range wins, quality values are ignored.
+// none means neither json nor raw bytes are acceptable
+fn prefers_raw(headers: &HeaderMap) -> Option<bool> {
+    let Some(accept) = headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .filter(|accept| !accept.trim().is_empty())
+    else {
+        return Some(false);
+    };
+
+    accept
+        .split(',')
+        .map(media_type)
+        .find_map(|media_type| match media_type {
+            OCTET_STREAM => Some(true),
+            JSON | "application/*" | "*/*" => Some(false),
+            _ => None,
+        })
+}
+
+fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or_default().trim()
+}
+
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+pub async fn scan_keys(
+    Query(query): Query<ScanQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let limit = page_limit(query.limit);
+    let start = query.cursor.or(query.start);
+
+    // fetch one extra entry to know whether there is a next page
+    let page = blocking(state, move |buckets| {
+        buckets
+            .scan(start.as_ref(), query.end.as_ref())
+            .and_then(|entries| entries.take(limit + 1).collect())
+    })
+    .await;
+
+    page_response(page, limit, "scan")
+}
+
+pub async fn scan_prefix(
+    Path(prefix): Path<String>,
+    Query(query): Query<PageQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let limit = page_limit(query.limit);
+
+    let page = blocking(state, move |buckets| {
+        buckets
+            .scan_prefix(&prefix, query.cursor.as_ref())
+            .and_then(|entries| entries.take(limit + 1).collect())
+    })
+    .await;
+
+    page_response(page, limit, "scan prefix")
+}
+
+pub async fn count_prefix(
+    Path(prefix): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let counted = {
+        let prefix = prefix.clone();
+        blocking(state, move |buckets| buckets.count_prefix(&prefix)).await
+    };
+
+    match counted {
+        Ok(count) => (StatusCode::OK, Json(CountResponse { prefix, count })).into_response(),
+        Err(e) => {
+            println!("[ERROR] count prefix: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: "Internal server error".to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+// entries per page, at least one: an empty page would hand back the cursor it was given
+fn page_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_SCAN_LIMIT).clamp(1, MAX_SCAN_LIMIT)
+}
+
+// `page` holds up to `limit + 1` entries, the extra one becomes the cursor of the next page
+fn page_response(page: Result<Vec<(Key, Value)>>, limit: usize, operation: &str) -> Response {
+    let mut entries = match page {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("[ERROR] {operation}: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: "Internal server error".to_string(),
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    let next_cursor = (entries.len() > limit)
+        .then(|| entries.pop().map(|(key, _)| key))
+        .flatten();
+    let entries = entries
+        .into_iter()
+        .map(|(key, value)| KeyValue {
+            key,
+            value: ValueResponse::new(&value),
+        })
+        .collect();
+
+    (
+        StatusCode::OK,
+        Json(ScanResponse {
+            entries,
+            next_cursor,
+        }),
+    )
+        .into_response()
+}
+
+pub async fn get_stats(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.buckets().stats())
+}
+
+/// any other method on the path of an endpoint, a write to a reserved key like any other
+pub async fn reserved_key() -> StatusCode {
+    StatusCode::BAD_REQUEST
+}
+
+/// applies every operation in the body or none of them
+pub async fn write_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let Ok(operations) = serde_json::from_slice::<Vec<BatchOperation>>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let options = match write_options(&headers) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+
+    let mut batch = WriteBatch::new();
+    for operation in operations {
+        match operation.into_entry() {
+            Ok(entry) => batch.add(entry),
+            Err(status) => return status,
+        }
+    }
+
+    match blocking(state, move |buckets| buckets.write(batch, options)).await {
+        Ok(_) => StatusCode::OK,
+        Err(KvError::UnknownMergeOperator(_)) => StatusCode::BAD_REQUEST,
+        Err(e) => {
+            println!("[ERROR] batch: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// applies the writes of the body only if every key it read is still at the version
+/// it was read at, as one atomic batch
+///
+/// keys written concurrently conflict, like keys read at another version, with 409
+pub async fn write_transaction(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let Ok(request) = serde_json::from_slice::<TransactionRequest>(&body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let options = match write_options(&headers) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+    let writes = match request
+        .writes
+        .into_iter()
+        .map(BatchOperation::into_entry)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(writes) => writes,
+        Err(status) => return status,
+    };
+
+    let commit = move |buckets: &MemTable| {
+        let mut transaction = buckets.transaction();
+
+        for read in request.reads {
+            let version = transaction.get_entry(&read.key)?.map(|entry| entry.seq());
+
+            if version != read.version {
+                return Err(KvError::Conflict(read.key));
+            }
+        }
+
+        for write in writes {
+            transaction.add(write);
+        }
+
+        transaction.commit_with(options)
+    };
+
+    match blocking(state, commit).await {
+        Ok(_) => StatusCode::OK,
+        Err(KvError::Conflict(_)) => StatusCode::CONFLICT,
+        Err(KvError::UnknownMergeOperator(_)) => StatusCode::BAD_REQUEST,
+        Err(e) => {
+            println!("[ERROR] transaction: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// adds `by` (default 1, negative to decrement) to the integer stored at the key,
+/// without reading it first. a missing value, or one that is not an integer, counts as 0
+/// and the result saturates
+///
+/// honors `If-Match` and `If-None-Match` like `put_key`
+pub async fn increment_key(
+    Path(key): Path<Key>,
+    Query(query): Query<IncrementQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    let operand = merge::increment_operand(query.by.unwrap_or(1));
+
+    merge_write(
+        state,
+        &headers,
+        SstEntry::new_merge(key, merge::ADD, operand),
+    )
+    .await
+}
+
+/// applies the operand with the named merge operator, without reading the value first.
+/// the operand is the body, taken like the value of `put_key`
+///
+/// built-in operators are `add`, `append`, `max` and `set_union`, others give 400
+pub async fn merge_key(
+    Path((key, operator)): Path<(Key, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let operand = match request_value(&headers, &body) {
+        Ok((operand, _)) => operand,
+        Err(status) => return status.into_response(),
+    };
+
+    merge_write(state, &headers, SstEntry::new_merge(key, operator, operand)).await
+}
+
+// writes a merge operand, honoring `If-Match` and `If-None-Match` like `put_key`
+async fn merge_write(state: AppState, headers: &HeaderMap, entry: SstEntry) -> Response {
+    let options = match write_options(headers) {
+        Ok(options) => options,
+        Err(status) => return status.into_response(),
+    };
+
+    let batch = match conditional_batch(headers, entry) {
+        Ok(batch) => batch,
+        Err(status) => return status.into_response(),
+    };
+
+    match blocking(state, move |buckets| buckets.write(batch, options)).await {
+        Ok(version) => (StatusCode::OK, [(ETAG, etag(version))]).into_response(),
+        Err(KvError::PreconditionFailed(_)) => StatusCode::PRECONDITION_FAILED.into_response(),
+        Err(KvError::UnknownMergeOperator(_)) => StatusCode::BAD_REQUEST.into_response(),
+        Err(e) => {
+            println!("[ERROR] merge: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// honors `If-Match` and `If-None-Match` like `put_key`
+pub async fn delete_key(
+    Path(key): Path<Key>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> StatusCode {
+    let options = match write_options(&headers) {
+        Ok(options) => options,
+        Err(status) => return status,
+    };
+
+    let batch = match conditional_batch(&headers, SstEntry::new_delete(key)) {
+        Ok(batch) => batch,
+        Err(status) => return status,
+    };
+
+    match blocking(state, move |buckets| buckets.write(batch, options)).await {
         Ok(_) => StatusCode::OK,
+        Err(KvError::PreconditionFailed(_)) => StatusCode::PRECONDITION_FAILED,
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
@@ -1,24 +1,66 @@
-use crate::routes::{delete_key, get_key, put_key};
-use crate::{error::Result, memtable::MemTable};
+use crate::routes::{
+    count_prefix, delete_key, get_key, get_stats, increment_key, merge_key, put_key, reserved_key,
+    scan_keys, scan_prefix, write_batch, write_transaction,
+};
+use crate::{error::Result, memtable::MemTable, options::Options};
 use axum::{
     Router,
-    routing::{delete, get, put},
+    routing::{delete, get, post, put},
+};
+use std::{
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
 };
-use std::sync::{Arc, RwLock};
 use tokio::net::TcpListener;
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct AppState {
-    buckets: Arc<RwLock<MemTable>>,
+    // the memtable handles its own concurrency, requests share it without a lock
+    buckets: Arc<MemTable>,
 }
 
 impl AppState {
-    pub fn buckets(&self) -> &Arc<RwLock<MemTable>> {
+    /// starts the memtable, and the expiry sweep if enabled
+    pub fn open(options: Options) -> Result<Self> {
+        Self::open_with(options, |_| {})
+    }
+
+    /// like `open`, with `setup` called on the memtable before it starts,
+    /// e.g. to register merge operators
+    pub fn open_with(options: Options, setup: impl FnOnce(&mut MemTable)) -> Result<Self> {
+        let expiry_sweep_interval = Duration::from_millis(options.expiry_sweep_interval_ms);
+
+        let mut buckets = MemTable::new(options);
+        setup(&mut buckets);
+        buckets.startup()?;
+        let buckets = Arc::new(buckets);
+
+        if !expiry_sweep_interval.is_zero() {
+            let buckets = Arc::downgrade(&buckets);
+            thread::spawn(move || sweep_expired(buckets, expiry_sweep_interval));
+        }
+
+        Ok(Self { buckets })
+    }
+
+    pub fn buckets(&self) -> &Arc<MemTable> {
         &self.buckets
     }
+}
+
+// writes tombstones over expired keys every `interval`, until the memtable is dropped
+fn sweep_expired(buckets: Weak<MemTable>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
 
-    pub fn startup(&self) -> Result<()> {
-        self.buckets.write().unwrap().startup()
+        let Some(buckets) = buckets.upgrade() else {
+            break;
+        };
+
+        if let Err(e) = buckets.sweep_expired() {
+            println!("[ERROR] expiry sweep: {e}");
+        }
     }
 }
 
@@ -26,22 +68,34 @@ impl AppState {
 pub struct Server;
 
 impl Server {
-    pub fn router() -> Result<Router> {
-        let app_state = AppState::default();
-        app_state.startup()?;
+    pub fn router(options: Options) -> Result<Router> {
+        Self::router_with(options, |_| {})
+    }
+
+    /// like `router`, see `AppState::open_with`
+    pub fn router_with(options: Options, setup: impl FnOnce(&mut MemTable)) -> Result<Router> {
+        let app_state = AppState::open_with(options, setup)?;
 
         Ok(Router::new()
+            .route("/", get(scan_keys))
+            // endpoint names are keys starting with `_`, which no write may take
+            .route("/_stats", get(get_stats).fallback(reserved_key))
+            .route("/_batch", post(write_batch).fallback(reserved_key))
+            .route("/_txn", post(write_transaction).fallback(reserved_key))
+            .route("/_prefix/{prefix}", get(scan_prefix))
+            .route("/_prefix/{prefix}/count", get(count_prefix))
             .route("/{key}", get(get_key))
             .route("/{key}", put(put_key))
             .route("/{key}", delete(delete_key))
+            .route("/{key}/incr", post(increment_key))
+            .route("/{key}/merge/{operator}", post(merge_key))
             .with_state(app_state))
     }
 
-    pub async fn run(port: u16) -> Result<()> {
-        let address = format!("127.0.0.1:{port}");
-        let listener = TcpListener::bind(address).await?;
+    pub async fn run(options: Options) -> Result<()> {
+        let listener = TcpListener::bind(&options.bind_address).await?;
 
-        let router = Self::router()?;
+        let router = Self::router(options)?;
 
         axum::serve(listener, router).await?;
 
@@ -0,0 +1,38 @@
+use std::{
+    hash::{BuildHasher, Hash, RandomState},
+    sync::RwLock,
+};
+
+/// a value split by key hash over shards that each have their own lock,
+/// so threads working on different keys rarely wait on each other
+pub struct Sharded<T> {
+    shards: Box<[RwLock<T>]>,
+    hasher: RandomState,
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Self {
+            shards: (0..Self::SHARDS)
+                .map(|_| RwLock::new(T::default()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<T> Sharded<T> {
+    const SHARDS: usize = 64;
+
+    /// the shard holding `key`
+    pub fn shard(&self, key: &(impl Hash + ?Sized)) -> &RwLock<T> {
+        let hash = self.hasher.hash_one(key) as usize;
+
+        &self.shards[hash % self.shards.len()]
+    }
+
+    /// every shard, for the rare reader that needs all of them
+    pub fn iter(&self) -> impl Iterator<Item = &RwLock<T>> {
+        self.shards.iter()
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, ThreadId},
+};
+
+use crate::shard::Sharded;
+
+/// the newest write readers may see, and the sequence numbers pinned by live snapshots
+///
+/// an older version of a key is kept as long as a snapshot between it and the next newer
+/// version still reads it, flushes and compactions fold it away otherwise.
+/// shared between the memtable and the flush and compaction workers
+#[derive(Default)]
+pub struct Snapshots {
+    // concurrency safety:
+    // the commit leader moves it forward once a group is in the memtable,
+    // readers only load it under the lock of the shard they pin in
+    visible_seq: AtomicU64,
+    // concurrency safety:
+    // pin counts, sharded by the pinning thread so concurrent reads rarely share a lock.
+    // a snapshot pins and unpins under its shard lock, flushes and compactions
+    // copy every shard out once their inputs are picked
+    pinned: Sharded<BTreeMap<u64, usize>>,
+}
+
+impl Snapshots {
+    /// pins the newest visible write until the returned snapshot is dropped
+    pub fn latest(self: &Arc<Self>) -> Snapshot {
+        let thread = thread::current().id();
+        let mut pinned = self.pinned.shard(&thread).write().unwrap();
+
+        // loaded under the lock: a flush or compaction that copied this shard before
+        // only holds writes up to here, of which this snapshot reads the newest versions
+        let seq = self.visible_seq.load(Ordering::SeqCst);
+        *pinned.entry(seq).or_default() += 1;
+
+        Snapshot {
+            seq,
+            thread,
+            snapshots: self.clone(),
+        }
+    }
+
+    /// makes writes up to `seq` visible to snapshots taken from now on
+    pub fn publish(&self, seq: u64) {
+        self.visible_seq.fetch_max(seq, Ordering::SeqCst);
+    }
+
+    /// pinned sequence numbers, in order
+    pub fn pinned(&self) -> Vec<u64> {
+        let mut pinned: Vec<u64> = self
+            .pinned
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().keys().copied().collect::<Vec<_>>())
+            .collect();
+        pinned.sort_unstable();
+        pinned.dedup();
+
+        pinned
+    }
+
+    fn unpin(&self, seq: u64, thread: ThreadId) {
+        let mut pinned = self.pinned.shard(&thread).write().unwrap();
+
+        if let Some(count) = pinned.get_mut(&seq) {
+            *count -= 1;
+
+            if *count == 0 {
+                pinned.remove(&seq);
+            }
+        }
+    }
+}
+
+/// point-in-time view of the store, as of the write numbered `seq`
+///
+/// reads through a snapshot see every write up to `seq` and none after it,
+/// the versions they need are kept until the snapshot is dropped
+pub struct Snapshot {
+    seq: u64,
+    // the thread it was pinned on, which picks its shard wherever it is dropped
+    thread: ThreadId,
+    snapshots: Arc<Snapshots>,
+}
+
+impl Snapshot {
+    /// sequence number of the newest write the snapshot sees
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.snapshots.unpin(self.seq, self.thread);
+    }
+}
+
+/// whether a snapshot in `pinned` reads the version numbered `older` rather than the next
+/// newer one, numbered `newer`, so the two have to be kept apart
+pub fn separates(pinned: &[u64], older: u64, newer: u64) -> bool {
+    let first = pinned.partition_point(|seq| *seq < older);
+
+    pinned.get(first).is_some_and(|seq| *seq < newer)
+}
@@ -0,0 +1,733 @@
+use std::{
+    cmp,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::{
+    bloom::BloomFilter,
+    cache::BlockCache,
+    error::{KvError, Result},
+    memtable::{Key, SstEntry, Value},
+    merge::MergeOperand,
+};
+
+// file layout:
+// [data block 0] ... [data block n] [index block] [filter block] [footer]
+//
+// every block is length-prefixed and checksummed:
+// [payload length: u32] [payload] [crc32 of payload: u32]
+//
+// data block payload: entries sorted by key, newest version first, then the offset of each entry
+// ([key length: u32] [key] [kind: u8] [seq: u64] [expiry: u64, expiring puts only]
+//  [value, puts only] [operands, merges only])*
+// expiry: unix milliseconds
+// value: [value length: u32] [value]
+// operands: [operand count: u32] ([operator length: u32] [operator] [operand length: u32] [operand])*
+// [entry offset: u32]* [entry count: u32]
+//
+// index block payload: one handle per data block, then the largest key and sequence number
+// in the file, and the earliest expiry, u64::MAX if nothing expires
+// [block count: u32] ([first key length: u32] [first key] [offset: u64] [length: u32])*
+// [max key length: u32] [max key] [max seq: u64] [min expiry: u64]
+//
+// filter block payload: bloom filter over every key in the file
+// [hash count: u32] [bits]
+//
+// footer (fixed size):
+// [index offset: u64] [index length: u32] [filter offset: u64] [filter length: u32]
+// [version: u32] [magic: u64]
+const MAGIC: u64 = 0x6b76_5f73_7374_0001;
+const FORMAT_VERSION: u32 = 1;
+const FOOTER_SIZE: usize = 8 + 4 + 8 + 4 + 4 + 8;
+const BLOCK_SIZE: usize = 4096;
+
+const PUT_KIND: u8 = 1;
+const DELETE_KIND: u8 = 2;
+const MERGE_KIND: u8 = 3;
+const EXPIRING_PUT_KIND: u8 = 4;
+
+/// location of a data block inside an sst file
+#[derive(Clone)]
+struct BlockHandle {
+    first_key: Key,
+    offset: u64,
+    len: u32,
+}
+
+/// writes entries, which must be added in key order, newest version first, to a new sst file
+pub struct SstWriter {
+    file: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    block_first_key: Option<Key>,
+    block_offsets: Vec<u32>,
+    index: Vec<BlockHandle>,
+    last_key: Key,
+    max_seq: u64,
+    min_expires_at: u64,
+    key_hashes: Vec<u64>,
+    bloom_false_positive_rate: f64,
+}
+
+impl SstWriter {
+    pub fn create(path: impl AsRef<Path>, bloom_false_positive_rate: f64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            block_first_key: None,
+            block_offsets: Vec::new(),
+            index: Vec::new(),
+            last_key: Key::new(),
+            max_seq: 0,
+            min_expires_at: u64::MAX,
+            key_hashes: Vec::new(),
+            bloom_false_positive_rate,
+        })
+    }
+
+    pub fn add(&mut self, entry: &SstEntry) -> Result<()> {
+        if self.block_first_key.is_none() {
+            self.block_first_key = Some(entry.key().clone());
+        }
+
+        self.block_offsets.push(self.block.len() as u32);
+        encode_entry(&mut self.block, entry);
+        self.last_key.clone_from(entry.key());
+        self.max_seq = self.max_seq.max(entry.seq());
+        if let Some(expires_at) = entry.expires_at() {
+            self.min_expires_at = self.min_expires_at.min(expires_at);
+        }
+        self.key_hashes
+            .push(BloomFilter::hash(entry.key().as_bytes()));
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    /// bytes of entries added so far, before the index and filter
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+
+        let mut index = Vec::new();
+        put_u32(&mut index, self.index.len() as u32);
+        for handle in &self.index {
+            put_bytes(&mut index, handle.first_key.as_bytes());
+            put_u64(&mut index, handle.offset);
+            put_u32(&mut index, handle.len);
+        }
+        put_bytes(&mut index, self.last_key.as_bytes());
+        put_u64(&mut index, self.max_seq);
+        put_u64(&mut index, self.min_expires_at);
+
+        let index_offset = self.offset;
+        let index_len = self.write_block(&index)?;
+
+        let mut filter = Vec::new();
+        BloomFilter::build(&self.key_hashes, self.bloom_false_positive_rate).encode(&mut filter);
+
+        let filter_offset = self.offset;
+        let filter_len = self.write_block(&filter)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        put_u64(&mut footer, index_offset);
+        put_u32(&mut footer, index_len);
+        put_u64(&mut footer, filter_offset);
+        put_u32(&mut footer, filter_len);
+        put_u32(&mut footer, FORMAT_VERSION);
+        put_u64(&mut footer, MAGIC);
+        self.file.write_all(&footer)?;
+
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let Some(first_key) = self.block_first_key.take() else {
+            return Ok(());
+        };
+
+        let offsets = std::mem::take(&mut self.block_offsets);
+        for offset in &offsets {
+            put_u32(&mut self.block, *offset);
+        }
+        put_u32(&mut self.block, offsets.len() as u32);
+
+        let block = std::mem::take(&mut self.block);
+        let offset = self.offset;
+        let len = self.write_block(&block)?;
+
+        self.index.push(BlockHandle {
+            first_key,
+            offset,
+            len,
+        });
+        self.block = block;
+        self.block.clear();
+
+        Ok(())
+    }
+
+    // returns the number of bytes written, including the length prefix and checksum
+    fn write_block(&mut self, payload: &[u8]) -> Result<u32> {
+        let checksum = crc32fast::hash(payload);
+
+        self.file.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.file.write_all(payload)?;
+        self.file.write_all(&checksum.to_le_bytes())?;
+
+        let len = 4 + payload.len() as u32 + 4;
+        self.offset += len as u64;
+
+        Ok(len)
+    }
+}
+
+/// in-memory metadata of an sst file listed in the manifest
+///
+/// holds the bloom filter and sparse index, so a lookup reads one block,
+/// or a few when the versions of a key run over the end of one
+pub struct Sst {
+    path: PathBuf,
+    // key of the file's blocks in the cache
+    id: u64,
+    cache: Arc<BlockCache>,
+    size: u64,
+    filter: BloomFilter,
+    // first key of every data block, in key order
+    index: Vec<BlockHandle>,
+    max_key: Key,
+    max_seq: u64,
+    min_expires_at: Option<u64>,
+    // set once the file is no longer in the manifest,
+    // it is deleted when the last reader lets go of it
+    obsolete: AtomicBool,
+}
+
+impl Sst {
+    /// reads the metadata of a file, its data blocks are read through `cache`
+    pub fn open(path: PathBuf, cache: Arc<BlockCache>) -> Result<Self> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let footer = Footer::read(&mut file)?;
+        let filter = BloomFilter::decode(&read_block(
+            &mut file,
+            footer.filter_offset,
+            footer.filter_len,
+        )?)?;
+
+        let payload = read_block(&mut file, footer.index_offset, footer.index_len)?;
+        let mut payload = &payload[..];
+        let count = get_u32(&mut payload)?;
+        let index = (0..count)
+            .map(|_| {
+                Ok(BlockHandle {
+                    first_key: get_key(&mut payload)?,
+                    offset: get_u64(&mut payload)?,
+                    len: get_u32(&mut payload)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let max_key = get_key(&mut payload)?;
+        let max_seq = get_u64(&mut payload)?;
+        let min_expires_at =
+            Some(get_u64(&mut payload)?).filter(|expires_at| *expires_at != u64::MAX);
+
+        Ok(Self {
+            path,
+            id: cache.new_file_id(),
+            cache,
+            size,
+            filter,
+            index,
+            max_key,
+            max_seq,
+            min_expires_at,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// file size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// smallest and largest key in the file, none if it is empty
+    pub fn key_range(&self) -> Option<(&Key, &Key)> {
+        self.index
+            .first()
+            .map(|first| (&first.first_key, &self.max_key))
+    }
+
+    /// newest sequence number in the file
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    /// earliest expiry in the file, in unix milliseconds, none if nothing in it expires
+    pub fn min_expires_at(&self) -> Option<u64> {
+        self.min_expires_at
+    }
+
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    /// false means the key falls outside the keys stored in this file
+    pub fn in_range(&self, key: &Key) -> bool {
+        self.index
+            .first()
+            .is_some_and(|first| first.first_key <= *key && *key <= self.max_key)
+    }
+
+    /// false means the key is definitely not in this file
+    pub fn may_contain(&self, key: &Key) -> bool {
+        self.filter.may_contain(key.as_bytes())
+    }
+
+    /// versions of a key numbered `seq` or lower, newest first
+    pub fn versions(&self, key: &Key, seq: u64) -> Result<Vec<SstEntry>> {
+        // the versions start in the last block starting before the key,
+        // and may run on into the blocks starting with it
+        let first_block = self
+            .index
+            .partition_point(|handle| handle.first_key < *key)
+            .saturating_sub(1);
+
+        let mut file = None;
+        let mut versions = Vec::new();
+
+        for handle in &self.index[first_block..] {
+            if handle.first_key > *key {
+                break;
+            }
+
+            let block = self.block(&mut file, handle, true)?;
+            versions.extend(
+                block
+                    .versions(key)?
+                    .into_iter()
+                    .filter(|entry| entry.seq() <= seq),
+            );
+        }
+
+        Ok(versions)
+    }
+
+    /// iterates every entry in key order, reading one block at a time
+    pub fn iter(self: &Arc<Self>) -> Result<SstIterator> {
+        Ok(SstIterator {
+            sst: self.clone(),
+            file: None,
+            next_block: 0,
+            entries: Vec::new().into_iter(),
+        })
+    }
+
+    /// iterates entries with keys at or after `start`, skipping earlier blocks
+    pub fn iter_from(self: &Arc<Self>, start: &Key) -> Result<SstIterator> {
+        // versions of `start` may begin in the block before the first one starting with it
+        let block_index = self
+            .index
+            .partition_point(|handle| handle.first_key < *start)
+            .saturating_sub(1);
+
+        let mut iter = SstIterator {
+            sst: self.clone(),
+            file: None,
+            next_block: block_index,
+            entries: Vec::new().into_iter(),
+        };
+
+        if let Some(handle) = self.index.get(block_index) {
+            let mut entries = self.block(&mut iter.file, handle, false)?.entries()?;
+            entries.retain(|entry| entry.key() >= start);
+
+            iter.next_block += 1;
+            iter.entries = entries.into_iter();
+        }
+
+        Ok(iter)
+    }
+
+    /// false means no key in `[start, end)` can be in this file
+    pub fn overlaps(&self, start: Option<&Key>, end: Option<&Key>) -> bool {
+        let Some(first) = self.index.first() else {
+            return false;
+        };
+
+        start.is_none_or(|start| *start <= self.max_key)
+            && end.is_none_or(|end| first.first_key < *end)
+    }
+
+    // the block at `handle`, from the cache or else from `file`, opened on the first miss.
+    // only point lookups `fill` the cache, so scans and compactions do not push out hot blocks
+    fn block(
+        &self,
+        file: &mut Option<File>,
+        handle: &BlockHandle,
+        fill: bool,
+    ) -> Result<Arc<Block>> {
+        if let Some(block) = self.cache.get(self.id, handle.offset) {
+            return Ok(block);
+        }
+
+        if file.is_none() {
+            *file = Some(File::open(&self.path)?);
+        }
+        let file = file.as_mut().expect("file was just opened");
+        let block = Arc::new(Block::read(file, handle)?);
+
+        if fill {
+            self.cache.insert(self.id, handle.offset, block.clone());
+        }
+
+        Ok(block)
+    }
+}
+
+impl Drop for Sst {
+    fn drop(&mut self) {
+        // nothing reads the blocks once the last reader lets go, and the id is not reused
+        self.cache.invalidate(self.id);
+
+        if self.obsolete.load(Ordering::SeqCst)
+            && let Err(e) = fs::remove_file(&self.path)
+        {
+            println!("[ERROR] remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+pub struct SstIterator {
+    sst: Arc<Sst>,
+    // opened on the first block not in the cache
+    file: Option<File>,
+    next_block: usize,
+    entries: std::vec::IntoIter<SstEntry>,
+}
+
+impl Iterator for SstIterator {
+    type Item = Result<SstEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+
+            let handle = self.sst.index.get(self.next_block)?;
+            self.next_block += 1;
+
+            match self
+                .sst
+                .block(&mut self.file, handle, false)
+                .and_then(|block| block.entries())
+            {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    // stop after the first error
+                    self.next_block = self.sst.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// decoded data block, with the offset of every entry for binary search
+pub struct Block {
+    data: Vec<u8>,
+    offsets: Vec<u32>,
+}
+
+impl Block {
+    fn read(file: &mut File, handle: &BlockHandle) -> Result<Self> {
+        let mut data = read_block(file, handle.offset, handle.len)?;
+
+        let Some(count_start) = data.len().checked_sub(4) else {
+            return Err(KvError::InvalidSst("block too small".to_string()));
+        };
+        let count = get_u32(&mut &data[count_start..])? as usize;
+
+        let Some(offsets_start) = count_start.checked_sub(count * 4) else {
+            return Err(KvError::InvalidSst(
+                "block offsets out of range".to_string(),
+            ));
+        };
+        let mut trailer = &data[offsets_start..count_start];
+        let offsets = (0..count)
+            .map(|_| get_u32(&mut trailer))
+            .collect::<Result<Vec<_>>>()?;
+
+        if offsets
+            .iter()
+            .any(|offset| *offset as usize >= offsets_start)
+        {
+            return Err(KvError::InvalidSst(
+                "block offsets out of range".to_string(),
+            ));
+        }
+
+        data.truncate(offsets_start);
+
+        Ok(Self { data, offsets })
+    }
+
+    /// approximate bytes the block takes in memory
+    pub fn size(&self) -> u64 {
+        (std::mem::size_of::<Self>() + self.data.len() + self.offsets.len() * 4) as u64
+    }
+
+    // entries of `key` in this block, newest first
+    fn versions(&self, key: &Key) -> Result<Vec<SstEntry>> {
+        // finds the first entry of the key
+        let (mut low, mut high) = (0, self.offsets.len());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let mut entry = &self.data[self.offsets[mid] as usize..];
+
+            match get_key(&mut entry)?.cmp(key) {
+                cmp::Ordering::Less => low = mid + 1,
+                cmp::Ordering::Greater | cmp::Ordering::Equal => high = mid,
+            }
+        }
+
+        let mut versions = Vec::new();
+        for offset in &self.offsets[low..] {
+            let entry = decode_entry(&mut &self.data[*offset as usize..])?;
+            if entry.key() != key {
+                break;
+            }
+
+            versions.push(entry);
+        }
+
+        Ok(versions)
+    }
+
+    fn entries(&self) -> Result<Vec<SstEntry>> {
+        let mut data = &self.data[..];
+        let mut entries = Vec::with_capacity(self.offsets.len());
+
+        while !data.is_empty() {
+            entries.push(decode_entry(&mut data)?);
+        }
+
+        Ok(entries)
+    }
+}
+
+struct Footer {
+    index_offset: u64,
+    index_len: u32,
+    filter_offset: u64,
+    filter_len: u32,
+}
+
+impl Footer {
+    fn read(file: &mut File) -> Result<Self> {
+        let file_len = file.metadata()?.len();
+
+        if file_len < FOOTER_SIZE as u64 {
+            return Err(KvError::InvalidSst("file too small".to_string()));
+        }
+
+        let mut footer = [0; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(file_len - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut footer)?;
+
+        let mut footer = &footer[..];
+        let index_offset = get_u64(&mut footer)?;
+        let index_len = get_u32(&mut footer)?;
+        let filter_offset = get_u64(&mut footer)?;
+        let filter_len = get_u32(&mut footer)?;
+        let version = get_u32(&mut footer)?;
+        let magic = get_u64(&mut footer)?;
+
+        if magic != MAGIC {
+            return Err(KvError::InvalidSst("bad magic number".to_string()));
+        }
+
+        if version != FORMAT_VERSION {
+            return Err(KvError::InvalidSst(format!(
+                "unsupported format version {version}"
+            )));
+        }
+
+        Ok(Self {
+            index_offset,
+            index_len,
+            filter_offset,
+            filter_len,
+        })
+    }
+}
+
+fn read_block(file: &mut File, offset: u64, len: u32) -> Result<Vec<u8>> {
+    if len < 8 {
+        return Err(KvError::InvalidSst("block too small".to_string()));
+    }
+
+    let mut block = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut block)?;
+
+    let mut header = &block[..4];
+    let payload_len = get_u32(&mut header)? as usize;
+
+    if payload_len + 8 != block.len() {
+        return Err(KvError::InvalidSst("block length mismatch".to_string()));
+    }
+
+    let mut trailer = &block[4 + payload_len..];
+    let expected_checksum = get_u32(&mut trailer)?;
+    let payload = &block[4..4 + payload_len];
+
+    if crc32fast::hash(payload) != expected_checksum {
+        return Err(KvError::InvalidChecksum);
+    }
+
+    Ok(payload.to_vec())
+}
+
+pub(crate) fn encode_entry(buf: &mut Vec<u8>, entry: &SstEntry) {
+    put_bytes(buf, entry.key().as_bytes());
+    buf.push(match entry {
+        SstEntry::Put(_) if entry.expires_at().is_some() => EXPIRING_PUT_KIND,
+        SstEntry::Put(_) => PUT_KIND,
+        SstEntry::Delete(_) => DELETE_KIND,
+        SstEntry::Merge(_) => MERGE_KIND,
+    });
+    put_u64(buf, entry.seq());
+
+    if let Some(expires_at) = entry.expires_at() {
+        put_u64(buf, expires_at);
+    }
+
+    if let Some(value) = entry.value() {
+        put_bytes(buf, value);
+    }
+
+    if let Some(operands) = entry.operands() {
+        put_u32(buf, operands.len() as u32);
+        for operand in operands {
+            put_bytes(buf, operand.operator.as_bytes());
+            put_bytes(buf, &operand.value);
+        }
+    }
+}
+
+pub(crate) fn decode_entry(buf: &mut &[u8]) -> Result<SstEntry> {
+    let key = get_key(buf)?;
+    let kind = get_u8(buf)?;
+    let seq = get_u64(buf)?;
+
+    let mut entry = match kind {
+        PUT_KIND => SstEntry::new_put(key, get_bytes(buf)?),
+        EXPIRING_PUT_KIND => {
+            let expires_at = get_u64(buf)?;
+
+            SstEntry::new_expiring_put(key, get_bytes(buf)?, expires_at)
+        }
+        DELETE_KIND => SstEntry::new_delete(key),
+        MERGE_KIND => {
+            let count = get_u32(buf)?;
+            let operands = (0..count)
+                .map(|_| {
+                    Ok(MergeOperand {
+                        operator: get_key(buf)?,
+                        value: get_bytes(buf)?,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            SstEntry::from_operands(key, operands)
+        }
+        kind => return Err(KvError::InvalidSst(format!("unknown entry kind {kind}"))),
+    };
+    entry.set_seq(seq);
+
+    Ok(entry)
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(KvError::InvalidSst("unexpected end of block".to_string()));
+    }
+
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+
+    Ok(head)
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8> {
+    Ok(take(buf, 1)?[0])
+}
+
+pub(crate) fn get_u32(buf: &mut &[u8]) -> Result<u32> {
+    let bytes = take(buf, 4)?;
+
+    Ok(u32::from_le_bytes(
+        bytes.try_into().expect("slice has 4 bytes"),
+    ))
+}
+
+fn get_u64(buf: &mut &[u8]) -> Result<u64> {
+    let bytes = take(buf, 8)?;
+
+    Ok(u64::from_le_bytes(
+        bytes.try_into().expect("slice has 8 bytes"),
+    ))
+}
+
+fn get_bytes(buf: &mut &[u8]) -> Result<Value> {
+    let len = get_u32(buf)? as usize;
+
+    Ok(take(buf, len)?.to_vec())
+}
+
+fn get_key(buf: &mut &[u8]) -> Result<Key> {
+    String::from_utf8(get_bytes(buf)?)
+        .map_err(|_| KvError::InvalidSst("key is not utf-8".to_string()))
+}
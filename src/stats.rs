@@ -0,0 +1,196 @@
+use serde::Serialize;
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// engine counters, updated from both readers and writers
+#[derive(Default)]
+pub struct Stats {
+    // filter said the key may be in the file, so the file was read
+    bloom_hits: AtomicU64,
+    // filter ruled the file out without touching disk
+    bloom_misses: AtomicU64,
+    // filter said the key may be in the file, but it was not
+    bloom_false_positives: AtomicU64,
+    // data blocks found in the block cache, and read from disk
+    block_cache_hits: AtomicU64,
+    block_cache_misses: AtomicU64,
+    // group commits, each one wal write
+    wal_batches: AtomicU64,
+    wal_batch_entries: AtomicU64,
+    wal_max_batch_size: AtomicU64,
+    wal_fsyncs: AtomicU64,
+    wal_fsync_rate: Mutex<RateWindow>,
+    flushes: AtomicU64,
+    flush_duration_ms: AtomicU64,
+    // writes that waited for the previous flush to free the immutable slot
+    flush_stalls: AtomicU64,
+    compactions: AtomicU64,
+    compaction_duration_ms: AtomicU64,
+    last_compaction_duration_ms: AtomicU64,
+    // size of the files merged away
+    compaction_bytes_read: AtomicU64,
+    // size of the files the merge produced
+    compaction_bytes_written: AtomicU64,
+    // expired keys the sweeper wrote tombstones for
+    expired_keys_swept: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    pub bloom_hits: u64,
+    pub bloom_misses: u64,
+    pub bloom_false_positives: u64,
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    pub wal_batches: u64,
+    pub wal_average_batch_size: f64,
+    pub wal_max_batch_size: u64,
+    pub wal_fsyncs: u64,
+    pub wal_fsyncs_per_second: f64,
+    pub flushes: u64,
+    pub flush_duration_ms: u64,
+    pub flush_stalls: u64,
+    pub compactions: u64,
+    pub compaction_duration_ms: u64,
+    pub last_compaction_duration_ms: u64,
+    pub compaction_bytes_read: u64,
+    pub compaction_bytes_written: u64,
+    pub expired_keys_swept: u64,
+}
+
+impl Stats {
+    pub fn record_bloom_hit(&self) {
+        self.bloom_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bloom_miss(&self) {
+        self.bloom_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bloom_false_positive(&self) {
+        self.bloom_false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_block_cache_hit(&self) {
+        self.block_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_block_cache_miss(&self) {
+        self.block_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_wal_batch(&self, entries: usize) {
+        self.wal_batches.fetch_add(1, Ordering::Relaxed);
+        self.wal_batch_entries
+            .fetch_add(entries as u64, Ordering::Relaxed);
+        self.wal_max_batch_size
+            .fetch_max(entries as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_fsync(&self) {
+        self.wal_fsyncs.fetch_add(1, Ordering::Relaxed);
+        self.wal_fsync_rate.lock().unwrap().record();
+    }
+
+    pub fn record_flush(&self, duration: Duration) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flush_duration_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_flush_stall(&self) {
+        self.flush_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_compaction(&self, duration: Duration, bytes_read: u64, bytes_written: u64) {
+        let duration_ms = duration.as_millis() as u64;
+
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_duration_ms
+            .fetch_add(duration_ms, Ordering::Relaxed);
+        self.last_compaction_duration_ms
+            .store(duration_ms, Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+    }
+
+    pub fn record_expired_swept(&self, keys: u64) {
+        self.expired_keys_swept.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let wal_batches = self.wal_batches.load(Ordering::Relaxed);
+        let wal_batch_entries = self.wal_batch_entries.load(Ordering::Relaxed);
+
+        StatsSnapshot {
+            wal_batches,
+            wal_average_batch_size: wal_batch_entries as f64 / wal_batches.max(1) as f64,
+            wal_max_batch_size: self.wal_max_batch_size.load(Ordering::Relaxed),
+            wal_fsyncs: self.wal_fsyncs.load(Ordering::Relaxed),
+            wal_fsyncs_per_second: self.wal_fsync_rate.lock().unwrap().rate(),
+            bloom_hits: self.bloom_hits.load(Ordering::Relaxed),
+            bloom_misses: self.bloom_misses.load(Ordering::Relaxed),
+            bloom_false_positives: self.bloom_false_positives.load(Ordering::Relaxed),
+            block_cache_hits: self.block_cache_hits.load(Ordering::Relaxed),
+            block_cache_misses: self.block_cache_misses.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            flush_duration_ms: self.flush_duration_ms.load(Ordering::Relaxed),
+            flush_stalls: self.flush_stalls.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compaction_duration_ms: self.compaction_duration_ms.load(Ordering::Relaxed),
+            last_compaction_duration_ms: self.last_compaction_duration_ms.load(Ordering::Relaxed),
+            compaction_bytes_read: self.compaction_bytes_read.load(Ordering::Relaxed),
+            compaction_bytes_written: self.compaction_bytes_written.load(Ordering::Relaxed),
+            expired_keys_swept: self.expired_keys_swept.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// events per second over the last full window of about a second
+struct RateWindow {
+    started: Instant,
+    count: u64,
+    rate: f64,
+}
+
+impl RateWindow {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn record(&mut self) {
+        self.count += 1;
+        self.roll();
+    }
+
+    fn rate(&mut self) -> f64 {
+        self.roll();
+        self.rate
+    }
+
+    // a window with no events for a while rolls over to 0 on the next read
+    fn roll(&mut self) {
+        let elapsed = self.started.elapsed();
+
+        if elapsed >= Self::WINDOW {
+            self.rate = self.count as f64 / elapsed.as_secs_f64();
+            self.started = Instant::now();
+            self.count = 0;
+        }
+    }
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            count: 0,
+            rate: 0.0,
+        }
+    }
+}
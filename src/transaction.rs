@@ -0,0 +1,107 @@
+use std::{collections::BTreeSet, time::SystemTime};
+
+use crate::{
+    batch::{Precondition, WriteBatch},
+    error::{KvError, Result},
+    memtable::{Key, MemTable, SstEntry, Value, unix_millis},
+    options::WriteOptions,
+    snapshot::Snapshot,
+};
+
+/// reads and writes across keys, committed atomically as one wal record
+///
+/// reads see the store as of when the transaction began, with its own writes applied.
+/// writes are buffered until commit, which fails with `KvError::Conflict` if a key read by
+/// the transaction was written since it began. nothing is locked meanwhile, a transaction
+/// that conflicts is meant to be retried from the start
+pub struct Transaction<'a> {
+    memtable: &'a MemTable,
+    snapshot: Snapshot,
+    // keys that must be unchanged since the snapshot for the commit to go through
+    reads: BTreeSet<Key>,
+    writes: WriteBatch,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(memtable: &'a MemTable, snapshot: Snapshot) -> Self {
+        Self {
+            memtable,
+            snapshot,
+            reads: BTreeSet::new(),
+            writes: WriteBatch::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>> {
+        Ok(self
+            .get_entry(key)?
+            .and_then(|entry| entry.value().cloned()))
+    }
+
+    /// put of a key, like `MemTable::get_entry`
+    ///
+    /// a key written by the transaction reads with its writes applied, at version 0
+    /// until they are committed
+    pub fn get_entry(&mut self, key: &Key) -> Result<Option<SstEntry>> {
+        self.reads.insert(key.clone());
+
+        let mut entry = self.memtable.get_entry_at(key, &self.snapshot)?;
+        for write in self
+            .writes
+            .entries()
+            .iter()
+            .filter(|write| write.key() == key)
+        {
+            let older = entry.unwrap_or_else(|| SstEntry::new_delete(key.clone()));
+            entry = Some(self.memtable.merge_operators().fold(older, write.clone())?);
+        }
+
+        let now = unix_millis(SystemTime::now());
+
+        Ok(entry.filter(|entry| entry.value().is_some() && !entry.is_expired(now)))
+    }
+
+    pub fn put(&mut self, key: Key, value: Value) {
+        self.writes.put(key, value);
+    }
+
+    pub fn delete(&mut self, key: Key) {
+        self.writes.delete(key);
+    }
+
+    /// applies `operand` with the merge operator named `operator`, see `MemTable::merge`
+    pub fn merge(&mut self, key: Key, operator: &str, operand: Value) {
+        self.writes.merge(key, operator, operand);
+    }
+
+    /// buffers any other write, such as an expiring put
+    pub fn add(&mut self, entry: SstEntry) {
+        self.writes.add(entry);
+    }
+
+    /// sequence number the transaction reads as of
+    pub fn seq(&self) -> u64 {
+        self.snapshot.seq()
+    }
+
+    /// applies every write, or fails with `KvError::Conflict` and applies none,
+    /// returns the sequence number of the last one
+    pub fn commit(self) -> Result<u64> {
+        self.commit_with(WriteOptions::default())
+    }
+
+    pub fn commit_with(mut self, options: WriteOptions) -> Result<u64> {
+        // checked by the commit leader, against every write committed before this one
+        for key in &self.reads {
+            self.writes.require(
+                key.clone(),
+                Precondition::UnchangedSince(self.snapshot.seq()),
+            );
+        }
+
+        match self.memtable.write(self.writes, options) {
+            Err(KvError::PreconditionFailed(key)) => Err(KvError::Conflict(key)),
+            result => result,
+        }
+    }
+}
@@ -1,153 +1,462 @@
-use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
     fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader, Write},
-    path::Path,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
 use crate::{
+    batch::WriteBatch,
+    crc32c,
     error::{KvError, Result},
-    memtable::{Key, SstEntry, Value},
+    manifest::sync_dir,
+    memtable::SstEntry,
+    options::{Options, SyncPolicy},
+    sst,
+    stats::Stats,
 };
 
-#[derive(Default)]
-pub struct Wal;
-
-impl Wal {
-    const WAL_PATH: &str = "data/wal/wal.db";
+// segment layout:
+// [magic: u64] [record]*
+//
+// record, checksummed so recovery can tell a torn or corrupt record from a good one:
+// [payload length: u32] [crc32c of type and payload: u32] [type: u8] [payload]
+//
+// entry record payload: one entry, encoded as in an sst data block
+// batch record payload: [entry count: u32] [entry]*, replayed all together or not at all
+const MAGIC: u64 = 0x6b76_5f77_616c_0001;
+const RECORD_HEADER_SIZE: usize = 4 + 4 + 1;
+
+const ENTRY_RECORD: u8 = 1;
+const BATCH_RECORD: u8 = 2;
+
+/// write-ahead log split into numbered segments, `wal-000001.log`, ...
+///
+/// writes go to the newest segment, which is sealed and replaced by a new one on flush.
+/// a sealed segment is deleted once the sst holding its entries is in the manifest
+pub struct Wal {
+    dir: PathBuf,
+    // concurrency safety:
+    // only the commit leader appends and rotates, the lock hands the segment
+    // between successive leaders
+    active: Mutex<Option<Segment>>,
+    sync: Arc<SyncState>,
+    sync_interval_bytes: u64,
+    salvage: bool,
+    // fsyncs writes made under `SyncPolicy::Interval`
+    _syncer: Syncer,
+}
 
-    pub fn startup(&self) -> Result<()> {
-        let wal_path = Path::new(Self::WAL_PATH);
+// the segment taking writes, kept open for the life of the segment
+struct Segment {
+    id: u64,
+    file: File,
+    // bytes of whole records written so far, the magic included
+    len: u64,
+}
 
-        if let Some(parent) = wal_path.parent() {
-            fs::create_dir_all(parent)?;
-        }
+// what the background syncer needs to fsync the active segment
+struct SyncState {
+    // concurrency safety:
+    // a second handle on the active segment, swapped on rotation.
+    // held around each fsync and across rotation, so an fsync never lands
+    // on the new segment while the old one still has unsynced bytes
+    file: Mutex<Option<File>>,
+    // bytes written under `SyncPolicy::Interval` since the last fsync
+    unsynced_bytes: AtomicU64,
+    stats: Arc<Stats>,
+}
 
-        if !wal_path.exists() {
-            File::create(wal_path)?;
+impl Wal {
+    pub fn new(options: &Options, stats: Arc<Stats>) -> Self {
+        let sync = Arc::new(SyncState {
+            file: Mutex::new(None),
+            unsynced_bytes: AtomicU64::new(0),
+            stats,
+        });
+
+        Self {
+            dir: options.wal_dir(),
+            active: Mutex::new(None),
+            _syncer: Syncer::start(
+                sync.clone(),
+                Duration::from_millis(options.sync_interval_ms),
+            ),
+            sync,
+            sync_interval_bytes: options.sync_interval_bytes,
+            salvage: options.salvage_wal,
         }
-
-        Ok(())
     }
 
-    pub fn existing_entries(&self) -> Result<HashMap<Key, SstEntry>> {
-        let wal_file = File::open(Self::WAL_PATH)?;
-        let reader = BufReader::new(wal_file);
-        let mut entries: HashMap<Key, SstEntry> = HashMap::new();
+    /// replays every live segment in order, then starts a new segment for writes
+    ///
+    /// the replayed segments stay until the memtable they are replayed into is flushed.
+    /// entries are returned in the order they were written
+    pub fn startup(&self) -> Result<Vec<SstEntry>> {
+        fs::create_dir_all(&self.dir)?;
+
+        let segments = self.segments()?;
+        let next_id = segments.last().map_or(1, |(id, _)| id + 1);
 
-        for line in reader.lines() {
-            let line = line?;
+        let mut entries = Vec::new();
+        for (index, (_, path)) in segments.iter().enumerate() {
+            let last = index + 1 == segments.len();
+            self.replay(path, last, &mut entries)?;
 
-            if line.trim().is_empty() {
-                continue;
+            // it is sealed once the next segment starts
+            if last {
+                File::open(path)?.sync_all()?;
             }
+        }
+
+        self.open_segment(next_id)?;
+
+        Ok(entries)
+    }
 
-            let Ok(entry) = Log::validate(&line) else {
-                break;
+    /// appends one record per batch with a single write,
+    /// and at most one fsync, depending on `sync_policy`
+    pub fn append(&self, batches: &[WriteBatch], sync_policy: SyncPolicy) -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut payload = Vec::new();
+        for batch in batches {
+            payload.clear();
+
+            let record_type = match batch.entries() {
+                [entry] => {
+                    sst::encode_entry(&mut payload, entry);
+                    ENTRY_RECORD
+                }
+                entries => {
+                    payload.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                    for entry in entries {
+                        sst::encode_entry(&mut payload, entry);
+                    }
+                    BATCH_RECORD
+                }
             };
+            encode_record(&mut buffer, record_type, &payload);
+        }
 
-            match entry {
-                Entry::Put { key, value } => {
-                    entries.insert(key.clone(), SstEntry::new_put(key, value));
+        {
+            let mut active = self.active.lock().unwrap();
+            let segment = active.as_mut().ok_or_else(Self::not_started)?;
+
+            // a record cut short would hide every record written after it from replay,
+            // so it is taken back out before anything else is appended
+            if let Err(e) = segment.file.write_all(&buffer) {
+                let len = segment.len;
+                let rolled_back = segment.file.set_len(len).and_then(|_| {
+                    segment.file.seek(SeekFrom::Start(len))?;
+                    Ok(())
+                });
+
+                // with the partial record stuck in it, the segment takes no more writes.
+                // replay cuts it off as a torn tail on the next startup
+                if let Err(rollback) = rolled_back {
+                    println!("[ERROR] wal: could not roll back a failed write: {rollback}");
+                    *active = None;
                 }
-                Entry::Delete { key } => {
-                    entries.insert(key.clone(), SstEntry::new_delete(key));
+
+                return Err(e.into());
+            }
+            segment.len += buffer.len() as u64;
+        }
+
+        match sync_policy {
+            SyncPolicy::Always => self.sync.sync(),
+            SyncPolicy::Interval => {
+                let unsynced = self
+                    .sync
+                    .unsynced_bytes
+                    .fetch_add(buffer.len() as u64, Ordering::SeqCst);
+
+                // too much at stake to wait for the timer
+                if unsynced + buffer.len() as u64 >= self.sync_interval_bytes {
+                    self.sync.sync()?;
                 }
+
+                Ok(())
             }
+            SyncPolicy::None => Ok(()),
         }
+    }
 
-        Ok(entries)
+    /// seals the active segment and starts the next one,
+    /// returns the id of the sealed segment
+    pub fn rotate(&self) -> Result<u64> {
+        let id = self
+            .active
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(Self::not_started)?
+            .id;
+
+        // a sealed segment is whole on disk, so replay can tell corruption in it from a torn write.
+        // this also keeps interval writes from being left behind in a segment the syncer no longer sees
+        self.sync.sync()?;
+
+        self.open_segment(id + 1)?;
+
+        Ok(id)
     }
 
-    pub fn put(&self, key: Key, value: Value) -> Result<()> {
-        let mut wal_file = OpenOptions::new().append(true).open(Self::WAL_PATH)?;
+    /// deletes every segment up to and including `id`,
+    /// once the memtable they were written for is recorded in the manifest
+    pub fn remove_segments(&self, id: u64) -> Result<()> {
+        for (_, path) in self
+            .segments()?
+            .into_iter()
+            .filter(|(other, _)| *other <= id)
+        {
+            fs::remove_file(path)?;
+        }
 
-        let entry = Entry::put(key, value);
-        let log = Log::new(entry)?;
-        let serialised = serde_json::to_string(&log)?;
+        sync_dir(&self.dir)
+    }
 
-        writeln!(wal_file, "{serialised}")?;
-        wal_file.flush()?;
-        wal_file.sync_all()?;
+    fn open_segment(&self, id: u64) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(self.segment_path(id))?;
+        file.write_all(&MAGIC.to_le_bytes())?;
+        file.sync_all()?;
+        sync_dir(&self.dir)?;
+
+        *self.sync.file.lock().unwrap() = Some(file.try_clone()?);
+        *self.active.lock().unwrap() = Some(Segment {
+            id,
+            file,
+            len: MAGIC.to_le_bytes().len() as u64,
+        });
 
         Ok(())
     }
 
-    pub fn delete(&self, key: Key) -> Result<()> {
-        let mut wal_file = OpenOptions::new().append(true).open(Self::WAL_PATH)?;
+    // live segments, oldest first
+    fn segments(&self) -> Result<Vec<(u64, PathBuf)>> {
+        let mut segments: Vec<_> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let id = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix("wal-")?
+                    .strip_suffix(".log")?
+                    .parse()
+                    .ok()?;
+
+                Some((id, path))
+            })
+            .collect();
+        segments.sort();
+
+        Ok(segments)
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("wal-{id:06}.log"))
+    }
+
+    fn not_started() -> KvError {
+        std::io::Error::other("wal is not taking writes, it was never started or a failed write could not be rolled back").into()
+    }
 
-        let entry = Entry::delete(key);
-        let log = Log::new(entry)?;
-        let serialised = serde_json::to_string(&log)?;
+    // replays a segment into `entries`.
+    // only the last segment can end in a write cut short by a crash: a record running past the
+    // end of the file, or a tail of zeros the file system allocated but never wrote. that tail is
+    // dropped. any other bad record is corruption of synced writes, in any segment, an error
+    // unless `salvage` is set, in which case the rest of that segment is skipped but kept on disk.
+    // there is no searching for good records past a bad one, a value can look like a record
+    fn replay(&self, path: &Path, last: bool, entries: &mut Vec<SstEntry>) -> Result<()> {
+        let data = fs::read(path)?;
+
+        if data.len() < MAGIC.to_le_bytes().len() {
+            // crashed while starting the segment, nothing was written to it
+            return Self::truncate(path, &data, 0);
+        }
 
-        writeln!(wal_file, "{serialised}")?;
-        wal_file.flush()?;
-        wal_file.sync_all()?;
+        let magic = u64::from_le_bytes(data[..8].try_into().expect("slice has 8 bytes"));
+        if magic != MAGIC {
+            return Err(KvError::CorruptWal(format!(
+                "{} is not a wal segment",
+                path.display()
+            )));
+        }
+
+        let mut offset = MAGIC.to_le_bytes().len();
+        while let Some((record_type, payload)) = decode_record(&data[offset..]) {
+            apply_record(record_type, payload, entries)?;
+            offset += RECORD_HEADER_SIZE + payload.len();
+        }
+
+        if offset == data.len() {
+            return Ok(());
+        }
+
+        if last && is_torn(&data[offset..]) {
+            return Self::truncate(path, &data, offset);
+        }
+
+        if !self.salvage {
+            return Err(KvError::CorruptWal(format!(
+                "{} at offset {offset}, start with --salvage-wal true to skip the rest of the segment",
+                path.display()
+            )));
+        }
+
+        println!(
+            "[WARN] wal: skipped {} corrupt bytes at offset {offset} of {}",
+            data.len() - offset,
+            path.display()
+        );
 
         Ok(())
     }
 
-    pub fn reset(&mut self) -> Result<()> {
-        let mut wal_file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(Self::WAL_PATH)?;
-        wal_file.flush()?;
-        wal_file.sync_all()?;
+    // drops everything from `offset` on
+    fn truncate(path: &Path, data: &[u8], offset: usize) -> Result<()> {
+        if offset == data.len() {
+            return Ok(());
+        }
+
+        println!(
+            "[WARN] wal: dropped {} bytes at the end of {}",
+            data.len() - offset,
+            path.display()
+        );
+
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(offset as u64)?;
+        file.sync_all()?;
 
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Log {
-    hash: u32,
-    entry: Entry,
+fn encode_record(buffer: &mut Vec<u8>, record_type: u8, payload: &[u8]) {
+    let mut checksum = Vec::with_capacity(1 + payload.len());
+    checksum.push(record_type);
+    checksum.extend_from_slice(payload);
+
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&crc32c::hash(&checksum).to_le_bytes());
+    buffer.extend_from_slice(&checksum);
 }
 
-impl Log {
-    pub fn new(entry: Entry) -> Result<Self> {
-        let serialised = serde_json::to_string(&entry)?;
-        let bytes = serialised.as_bytes();
-        let hash = crc32fast::hash(bytes);
+// whether the bad record at the start of `data` is a write cut short, rather than a corrupt one
+fn is_torn(data: &[u8]) -> bool {
+    if data.len() < RECORD_HEADER_SIZE || data.iter().all(|byte| *byte == 0) {
+        return true;
+    }
+
+    let len = u32::from_le_bytes(data[..4].try_into().expect("slice has 4 bytes")) as usize;
+
+    RECORD_HEADER_SIZE.saturating_add(len) > data.len()
+}
 
-        Ok(Self { hash, entry })
+// type and payload of the record at the start of `data`, none if it is torn or corrupt
+fn decode_record(data: &[u8]) -> Option<(u8, &[u8])> {
+    if data.len() < RECORD_HEADER_SIZE {
+        return None;
     }
 
-    pub fn validate(string: &str) -> Result<Entry> {
-        let string = string.trim_end();
+    let len = u32::from_le_bytes(data[..4].try_into().expect("slice has 4 bytes")) as usize;
+    let checksum = u32::from_le_bytes(data[4..8].try_into().expect("slice has 4 bytes"));
+    let end = RECORD_HEADER_SIZE.checked_add(len)?;
 
-        let Log {
-            hash: expected_hash,
-            entry,
-        } = serde_json::from_str(string)?;
-        let entry_str = serde_json::to_string(&entry)?;
-        let entry_bytes = entry_str.as_bytes();
+    if end > data.len() || crc32c::hash(&data[8..end]) != checksum {
+        return None;
+    }
 
-        let hash = crc32fast::hash(entry_bytes);
+    Some((data[8], &data[RECORD_HEADER_SIZE..end]))
+}
 
-        if expected_hash != hash {
-            return Err(KvError::InvalidChecksum);
+fn apply_record(record_type: u8, mut payload: &[u8], entries: &mut Vec<SstEntry>) -> Result<()> {
+    match record_type {
+        ENTRY_RECORD => {
+            entries.push(sst::decode_entry(&mut payload)?);
+
+            Ok(())
+        }
+        BATCH_RECORD => {
+            let count = sst::get_u32(&mut payload)?;
+            for _ in 0..count {
+                entries.push(sst::decode_entry(&mut payload)?);
+            }
+
+            Ok(())
+        }
+        _ => Err(KvError::CorruptWal(format!(
+            "unknown record type {record_type}"
+        ))),
+    }
+}
+
+impl SyncState {
+    // fsyncs the active segment, covering everything appended so far
+    fn sync(&self) -> Result<()> {
+        let file = self.file.lock().unwrap();
+        let Some(file) = file.as_ref() else {
+            return Ok(());
+        };
+        let unsynced = self.unsynced_bytes.swap(0, Ordering::SeqCst);
+
+        if let Err(e) = file.sync_data() {
+            self.unsynced_bytes.fetch_add(unsynced, Ordering::SeqCst);
+            return Err(e.into());
         }
+        self.stats.record_fsync();
 
-        Ok(entry)
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "op", rename_all = "lowercase")]
-enum Entry {
-    Put { key: Key, value: Value },
-    Delete { key: Key },
+/// fsyncs interval writes on a background timer
+struct Syncer {
+    sender: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
 }
 
-impl Entry {
-    pub fn put(key: Key, value: Value) -> Self {
-        Self::Put { key, value }
+impl Syncer {
+    fn start(sync: Arc<SyncState>, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let handle = thread::spawn(move || {
+            // the channel only ever closes, which stops the timer
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                if sync.unsynced_bytes.load(Ordering::SeqCst) == 0 {
+                    continue;
+                }
+
+                if let Err(e) = sync.sync() {
+                    println!("[ERROR] wal sync: {e}");
+                }
+            }
+        });
+
+        Self {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
     }
+}
 
-    pub fn delete(key: Key) -> Self {
-        Self::Delete { key }
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.sender.take();
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
     }
 }
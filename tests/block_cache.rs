@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use kv::{
+    cache::BlockCache,
+    memtable::SstEntry,
+    sst::{Sst, SstWriter},
+    stats::Stats,
+};
+
+const KEYS: usize = 2000;
+
+fn key(i: usize) -> String {
+    format!("key{i:05}")
+}
+
+// a file of many blocks, one version per key
+fn write_sst(path: &std::path::Path) {
+    let mut writer = SstWriter::create(path, 0.01).unwrap();
+    for i in 0..KEYS {
+        writer
+            .add(&SstEntry::new_put(key(i), vec![b'v'; 100]))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+#[test]
+fn test_blocks_are_evicted_and_invalidated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sst-1.sst");
+    write_sst(&path);
+
+    let capacity = 16 * 1024;
+    let stats = Arc::new(Stats::default());
+    let cache = Arc::new(BlockCache::new(capacity, stats.clone()));
+    let sst = Sst::open(path.clone(), cache.clone()).unwrap();
+
+    let read = |i: usize| {
+        let versions = sst.versions(&key(i), u64::MAX).unwrap();
+        assert_eq!(versions.len(), 1);
+    };
+
+    read(0);
+    read(0);
+    let snapshot = stats.snapshot();
+    assert_eq!(
+        (snapshot.block_cache_misses, snapshot.block_cache_hits),
+        (1, 1)
+    );
+
+    // reading every block keeps the cache within its capacity
+    for i in 0..KEYS {
+        read(i);
+        assert!(cache.size() <= capacity);
+    }
+    assert!(cache.size() > 0);
+
+    // the first block was the least recently used, it is long gone
+    let misses = stats.snapshot().block_cache_misses;
+    read(0);
+    assert_eq!(stats.snapshot().block_cache_misses, misses + 1);
+
+    // closing the file drops its blocks
+    drop(sst);
+    assert_eq!(cache.size(), 0);
+}
+
+#[test]
+fn test_zero_capacity_caches_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sst-1.sst");
+    write_sst(&path);
+
+    let stats = Arc::new(Stats::default());
+    let cache = Arc::new(BlockCache::new(0, stats.clone()));
+    let sst = Sst::open(path, cache.clone()).unwrap();
+
+    for _ in 0..3 {
+        sst.versions(&key(0), u64::MAX).unwrap();
+    }
+
+    assert_eq!(cache.size(), 0);
+    assert_eq!(stats.snapshot().block_cache_hits, 0);
+}
@@ -1,4 +1,5 @@
 use kv::error::Result;
+use kv::options::Options;
 use kv::server::Server;
 use reqwest::StatusCode;
 use serde_json::json;
@@ -8,9 +9,9 @@ use tokio::task::JoinHandle;
 
 pub const SERVER_ADDRESS: &str = "127.0.0.1:3000";
 
-async fn spawn_server() -> Result<JoinHandle<()>> {
+async fn spawn_server(options: Options) -> Result<JoinHandle<()>> {
     let listener = TcpListener::bind(SERVER_ADDRESS).await?;
-    let router = Server::router()?;
+    let router = Server::router(options)?;
 
     let handle = tokio::spawn(async move {
         axum::serve(listener, router)
@@ -22,11 +23,25 @@ async fn spawn_server() -> Result<JoinHandle<()>> {
 }
 
 pub async fn setup_server(random_restarts: bool) -> Result<()> {
+    // restarts reuse the same data directory, which is removed once the runtime shuts down
+    let data_dir = tempfile::tempdir()?;
+    let options = Options {
+        data_dir: data_dir.path().to_path_buf(),
+        bind_address: SERVER_ADDRESS.to_string(),
+        ..Options::default()
+    };
+
     tokio::spawn(async move {
+        let _data_dir = data_dir;
+
         loop {
-            let handle = spawn_server().await.expect("failed to spawn server");
+            let handle = spawn_server(options.clone())
+                .await
+                .expect("failed to spawn server");
 
             if !random_restarts {
+                // keep the data directory alive for as long as the server runs
+                let _ = handle.await;
                 break;
             }
 
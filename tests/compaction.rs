@@ -0,0 +1,140 @@
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use kv::{
+    compaction::{CompactionStrategy, LeveledCompaction, SizeTieredCompaction},
+    manifest::Manifest,
+    memtable::MemTable,
+    merge::MergeOperators,
+    options::{Options, SyncPolicy},
+    snapshot::Snapshots,
+    stats::Stats,
+};
+
+// writes overlapping rounds of puts and deletes, returning what each key should read
+fn fill(options: &Options) -> BTreeMap<String, Option<Vec<u8>>> {
+    let mut memtable = MemTable::new(options.clone());
+    memtable.startup().unwrap();
+
+    let mut expected = BTreeMap::new();
+    for round in 0..8 {
+        for i in (round * 37..4000).step_by(3 + round) {
+            let key = format!("key{i:05}");
+
+            if i % 11 == round {
+                memtable.delete(&key).unwrap();
+                expected.insert(key, None);
+            } else {
+                let value = format!("{round}-{}", "v".repeat(40)).into_bytes();
+                memtable.put(key.clone(), value.clone()).unwrap();
+                expected.insert(key, Some(value));
+            }
+        }
+    }
+
+    expected
+}
+
+// runs the strategy until it has nothing left to do
+fn compact(options: &Options, mut strategy: impl CompactionStrategy) -> Manifest {
+    let stats = Arc::new(Stats::default());
+    let manifest = Manifest::new(options, stats.clone());
+    manifest.load().unwrap();
+
+    let operators = MergeOperators::default();
+    let snapshots = Snapshots::default();
+    let mut runs = 0;
+    while strategy
+        .compact(&manifest, &operators, &snapshots, options, &stats)
+        .unwrap()
+    {
+        runs += 1;
+        assert!(runs < 1000, "compaction never settles");
+    }
+    assert!(runs > 0);
+
+    manifest
+}
+
+fn check_reads(options: &Options, expected: &BTreeMap<String, Option<Vec<u8>>>) {
+    let mut memtable = MemTable::new(options.clone());
+    memtable.startup().unwrap();
+
+    for (key, value) in expected {
+        assert_eq!(&memtable.get(key).unwrap(), value, "{key}");
+    }
+}
+
+fn options(data_dir: &Path) -> Options {
+    Options {
+        data_dir: data_dir.to_path_buf(),
+        memtable_size_bytes: 8 * 1024,
+        // compactions are run by the test, not in the background
+        compaction_threshold: usize::MAX,
+        level0_file_limit: 4,
+        level_base_bytes: 32 * 1024,
+        level_size_multiplier: 4,
+        max_levels: 4,
+        sync_policy: SyncPolicy::None,
+        ..Options::default()
+    }
+}
+
+#[test]
+fn test_leveled_invariants() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = options(data_dir.path());
+
+    let expected = fill(&options);
+    let manifest = compact(&options, LeveledCompaction::default());
+    let levels = manifest.levels();
+
+    assert!(levels.len() <= options.max_levels);
+    assert!(levels[0].len() < options.level0_file_limit);
+
+    let mut target = options.level_base_bytes;
+    for (level, files) in levels.iter().enumerate().skip(1) {
+        // files of a deeper level are sorted and do not overlap
+        for pair in files.windows(2) {
+            let (_, last) = pair[0].key_range().unwrap();
+            let (first, _) = pair[1].key_range().unwrap();
+            assert!(last < first, "level {level} files overlap");
+        }
+
+        // the last level takes whatever is pushed into it
+        if level + 1 < options.max_levels {
+            let bytes: u64 = files.iter().map(|sst| sst.size()).sum();
+            assert!(bytes < target, "level {level} holds {bytes} bytes");
+        }
+        target *= options.level_size_multiplier;
+    }
+    drop(manifest);
+
+    check_reads(&options, &expected);
+}
+
+#[test]
+fn test_size_tiered_invariants() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = options(data_dir.path());
+
+    let expected = fill(&options);
+    let manifest = compact(&options, SizeTieredCompaction);
+    let levels = manifest.levels();
+
+    // every run stays in level 0
+    assert!(levels.iter().skip(1).all(|files| files.is_empty()));
+
+    // no `size_tier_min_runs` adjacent runs are left within `size_tier_ratio` of each other
+    let sizes: Vec<_> = levels[0].iter().map(|sst| sst.size()).collect();
+    for window in sizes.windows(options.size_tier_min_runs) {
+        let min = *window.iter().min().unwrap();
+        let max = *window.iter().max().unwrap();
+        assert!(
+            max as f64 > min as f64 * options.size_tier_ratio,
+            "runs {sizes:?} were left unmerged"
+        );
+    }
+    drop(manifest);
+
+    check_reads(&options, &expected);
+}
@@ -0,0 +1,70 @@
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use kv::{
+    batch::WriteBatch,
+    memtable::{MemTable, unix_millis},
+    options::{Options, SyncPolicy, WriteOptions},
+};
+
+fn put_expiring(memtable: &MemTable, key: &str, expires_at: u64) {
+    let mut batch = WriteBatch::new();
+    batch.put_expiring(key.to_string(), b"1".to_vec(), expires_at);
+    memtable.write(batch, WriteOptions::default()).unwrap();
+}
+
+fn get(memtable: &MemTable, key: &str) -> Option<Vec<u8>> {
+    memtable.get(&key.to_string()).unwrap()
+}
+
+#[test]
+fn test_expired_puts_read_as_missing_and_are_swept() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.path().to_path_buf(),
+        memtable_size_bytes: 4 * 1024,
+        sync_policy: SyncPolicy::None,
+        ..Options::default()
+    });
+    memtable.startup().unwrap();
+
+    let now = unix_millis(SystemTime::now());
+    put_expiring(&memtable, "expired", now - 1);
+    put_expiring(&memtable, "soon", now + 300);
+    put_expiring(&memtable, "later", now + 3_600_000);
+    put_expiring(&memtable, "rewritten", now + 300);
+
+    assert_eq!(get(&memtable, "expired"), None);
+    assert_eq!(get(&memtable, "soon"), Some(b"1".to_vec()));
+
+    // fills the memtable past its size, so the expiring puts end up in a file
+    for i in 0..100 {
+        memtable.put(format!("filler{i:03}"), vec![0; 100]).unwrap();
+    }
+    let started = Instant::now();
+    while memtable.stats().flushes == 0 {
+        assert!(started.elapsed() < Duration::from_secs(30), "no flush ran");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    thread::sleep(Duration::from_millis(400));
+    memtable.put("rewritten".into(), b"2".to_vec()).unwrap();
+
+    assert_eq!(get(&memtable, "soon"), None);
+    assert_eq!(get(&memtable, "later"), Some(b"1".to_vec()));
+    let scanned: Vec<_> = memtable
+        .scan(None, None)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .filter(|key| !key.starts_with("filler"))
+        .collect();
+    assert_eq!(scanned, ["later", "rewritten"]);
+
+    // the rewritten key keeps its new value, the others get tombstones once
+    assert_eq!(memtable.sweep_expired().unwrap(), 2);
+    assert_eq!(memtable.sweep_expired().unwrap(), 0);
+    assert_eq!(get(&memtable, "rewritten"), Some(b"2".to_vec()));
+    assert_eq!(get(&memtable, "later"), Some(b"1".to_vec()));
+}
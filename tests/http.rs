@@ -0,0 +1,396 @@
+use kv::{options::Options, server::Server};
+use reqwest::{
+    Client, StatusCode,
+    header::{ETAG, EXPIRES, IF_MATCH, IF_NONE_MATCH},
+};
+use serde_json::json;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+// a server on a free port with a data directory of its own, which lives as long as the guard
+async fn spawn_server() -> (String, TempDir) {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = Options {
+        data_dir: data_dir.path().to_path_buf(),
+        ..Options::default()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = Server::router(options).unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    (format!("http://{address}"), data_dir)
+}
+
+#[tokio::test]
+async fn test_conditional_writes() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+    let put = |key: &str, value: u32| {
+        client
+            .put(format!("{url}/{key}"))
+            .json(&json!({ "value": value }))
+    };
+
+    let response = put("a", 1).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()[ETAG].clone();
+
+    // a stale version fails, the current one goes through
+    let response = put("a", 2)
+        .header(IF_MATCH, "\"999\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = put("a", 2).header(IF_MATCH, &etag).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // the version it matched is gone now
+    let response = client
+        .delete(format!("{url}/a"))
+        .header(IF_MATCH, &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    // create only if missing
+    let response = put("a", 3).header(IF_NONE_MATCH, "*").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = put("b", 3).header(IF_NONE_MATCH, "*").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // update only if present
+    let response = put("c", 4).header(IF_MATCH, "*").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let value: serde_json::Value = client
+        .get(format!("{url}/a"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(value, json!({ "value": 2 }));
+    let response = client.get(format!("{url}/c")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_transaction_conflicts() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+
+    let response = client
+        .put(format!("{url}/balance"))
+        .json(&json!({ "value": 10 }))
+        .send()
+        .await
+        .unwrap();
+    let version: u64 = response.headers()[ETAG]
+        .to_str()
+        .unwrap()
+        .trim_matches('"')
+        .parse()
+        .unwrap();
+
+    let transaction = |value: u32| {
+        client.post(format!("{url}/_txn")).json(&json!({
+            "reads": [
+                { "key": "balance", "version": version },
+                { "key": "audit", "version": null },
+            ],
+            "writes": [
+                { "op": "put", "key": "balance", "value": value },
+                { "op": "put", "key": "audit", "value": 1 },
+            ],
+        }))
+    };
+
+    let response = transaction(5).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // both reads are stale now, the second commit has to start over
+    let response = transaction(0).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let value: serde_json::Value = client
+        .get(format!("{url}/balance"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(value, json!({ "value": 5 }));
+}
+
+#[tokio::test]
+async fn test_expiry_past_http_dates_is_rejected() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+
+    let response = client
+        .put(format!("{url}/a"))
+        .json(&json!({ "value": 1, "ttl_seconds": 300_000_000_000u64 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .post(format!("{url}/_batch"))
+        .json(&json!([{ "op": "put", "key": "a", "value": 1, "ttl_seconds": u64::MAX }]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // the latest date there is still goes through, and reads back
+    let response = client
+        .put(format!("{url}/a"))
+        .header(EXPIRES, "Fri, 31 Dec 9999 23:59:59 GMT")
+        .json(&json!({ "value": 1 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(format!("{url}/a")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[EXPIRES], "Fri, 31 Dec 9999 23:59:59 GMT");
+}
+
+#[tokio::test]
+async fn test_scan_pages() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+
+    for i in 0..5 {
+        client
+            .put(format!("{url}/key{i}"))
+            .json(&json!({ "value": i }))
+            .send()
+            .await
+            .unwrap();
+    }
+    let scan = |query: String| {
+        let client = client.clone();
+        let url = url.clone();
+        async move {
+            client
+                .get(format!("{url}/?{query}"))
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap()
+        }
+    };
+
+    let page = scan("start=key1&limit=2".into()).await;
+    assert_eq!(
+        page,
+        json!({
+            "entries": [{ "key": "key1", "value": 1 }, { "key": "key2", "value": 2 }],
+            "next_cursor": "key3",
+        })
+    );
+
+    // the last page has no cursor
+    let page = scan("start=key1&limit=2&cursor=key3".into()).await;
+    assert_eq!(
+        page,
+        json!({
+            "entries": [{ "key": "key3", "value": 3 }, { "key": "key4", "value": 4 }],
+            "next_cursor": null,
+        })
+    );
+
+    // a page always moves the cursor on
+    let page = scan("limit=0".into()).await;
+    assert_eq!(
+        page,
+        json!({ "entries": [{ "key": "key0", "value": 0 }], "next_cursor": "key1" })
+    );
+
+    let page = scan("start=key1&end=key3".into()).await;
+    assert_eq!(page["entries"].as_array().unwrap().len(), 2);
+    assert_eq!(page["next_cursor"], json!(null));
+}
+
+#[tokio::test]
+async fn test_reserved_keys_are_not_written() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+
+    let responses = [
+        client
+            .put(format!("{url}/_stats"))
+            .json(&json!({ "value": 1 })),
+        client
+            .put(format!("{url}/_other"))
+            .json(&json!({ "value": 1 })),
+        client.delete(format!("{url}/_other")),
+        client.post(format!("{url}/_other/incr")),
+        client
+            .post(format!("{url}/_other/merge/max"))
+            .json(&json!({ "value": 1 })),
+        client
+            .post(format!("{url}/_batch"))
+            .json(&json!([{ "op": "put", "key": "_other", "value": 1 }])),
+        client
+            .post(format!("{url}/_txn"))
+            .json(&json!({ "writes": [{ "op": "delete", "key": "_other" }] })),
+    ];
+    for request in responses {
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // the endpoints keep their names
+    let response = client.get(format!("{url}/_stats")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client.get(format!("{url}/_other")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_prefix_pages_and_count() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+
+    for key in ["a", "ab", "abc", "abd", "ac", "\u{10FFFF}", "\u{10FFFF}z"] {
+        client
+            .put(format!("{url}/{key}"))
+            .json(&json!({ "value": 1 }))
+            .send()
+            .await
+            .unwrap();
+    }
+    let get = |path: String| {
+        let client = client.clone();
+        let url = url.clone();
+        async move {
+            client
+                .get(format!("{url}{path}"))
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap()
+        }
+    };
+
+    let page = get("/_prefix/ab?limit=2".into()).await;
+    assert_eq!(
+        page,
+        json!({
+            "entries": [{ "key": "ab", "value": 1 }, { "key": "abc", "value": 1 }],
+            "next_cursor": "abd",
+        })
+    );
+    let page = get("/_prefix/ab?limit=2&cursor=abd".into()).await;
+    assert_eq!(
+        page,
+        json!({ "entries": [{ "key": "abd", "value": 1 }], "next_cursor": null })
+    );
+
+    let count = get("/_prefix/ab/count".into()).await;
+    assert_eq!(count, json!({ "prefix": "ab", "count": 3 }));
+
+    // the largest char, percent encoded
+    let count = get("/_prefix/%F4%8F%BF%BF/count".into()).await;
+    assert_eq!(count, json!({ "prefix": "\u{10FFFF}", "count": 2 }));
+}
+
+#[tokio::test]
+async fn test_unknown_merge_operator_is_rejected() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{url}/a/merge/nope"))
+        .json(&json!({ "value": 1 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .post(format!("{url}/_batch"))
+        .json(&json!([{ "op": "merge", "key": "a", "operator": "nope", "value": 1 }]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = client
+        .post(format!("{url}/a/merge/max"))
+        .json(&json!({ "value": 4 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let value: serde_json::Value = client
+        .get(format!("{url}/a"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(value, json!({ "value": 4 }));
+}
+
+#[tokio::test]
+async fn test_durability_header() {
+    let (url, _data_dir) = spawn_server().await;
+    let client = Client::new();
+    let fsyncs = || async {
+        client
+            .get(format!("{url}/_stats"))
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap()["wal_fsyncs"]
+            .as_u64()
+            .unwrap()
+    };
+    let put = |durability: Option<&str>| {
+        let request = client.put(format!("{url}/a")).json(&json!({ "value": 1 }));
+
+        match durability {
+            Some(durability) => request.header("X-Kv-Durability", durability),
+            None => request,
+        }
+    };
+
+    // the server syncs every write by default, the header overrides it per request
+    let before = fsyncs().await;
+    assert_eq!(put(None).send().await.unwrap().status(), StatusCode::OK);
+    assert_eq!(fsyncs().await, before + 1);
+    let response = put(Some("none")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(fsyncs().await, before + 1);
+    let response = put(Some("always")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(fsyncs().await, before + 2);
+
+    // an unknown policy is rejected before anything is written
+    let response = client
+        .put(format!("{url}/b"))
+        .header("X-Kv-Durability", "sometimes")
+        .json(&json!({ "value": 1 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client.get(format!("{url}/b")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
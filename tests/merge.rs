@@ -0,0 +1,242 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use kv::{
+    compaction::{CompactionStrategy, LeveledCompaction},
+    error::KvError,
+    manifest::Manifest,
+    memtable::{Key, MemTable, Value},
+    merge::{Add, Append, Max, MergeOperator, MergeOperators, SetUnion},
+    options::{Options, SyncPolicy},
+    snapshot::Snapshots,
+    stats::Stats,
+};
+
+fn open(options: &Options) -> MemTable {
+    let mut memtable = MemTable::new(options.clone());
+    memtable.startup().unwrap();
+
+    memtable
+}
+
+fn options(data_dir: &Path) -> Options {
+    Options {
+        data_dir: data_dir.to_path_buf(),
+        sync_policy: SyncPolicy::None,
+        // compactions are run by the test, not in the background
+        compaction_threshold: usize::MAX,
+        ..Options::default()
+    }
+}
+
+// applies operands in order, starting from `existing`
+fn apply(operator: &dyn MergeOperator, existing: Option<&str>, operands: &[&str]) -> String {
+    let key = "key".to_string();
+    let mut value = existing.map(|existing| existing.as_bytes().to_vec());
+    for operand in operands {
+        value = Some(operator.merge(&key, value.as_deref(), operand.as_bytes()));
+    }
+
+    String::from_utf8(value.unwrap()).unwrap()
+}
+
+#[test]
+fn test_add() {
+    assert_eq!(apply(&Add, None, &["2", "-5"]), "-3");
+    assert_eq!(apply(&Add, Some("10"), &["1"]), "11");
+    // anything that is not an integer counts as 0
+    assert_eq!(apply(&Add, Some("\"text\""), &["1", "x"]), "1");
+    // saturating on the way, in order
+    assert_eq!(
+        apply(&Add, Some("10"), &[&i64::MAX.to_string(), "-5"]),
+        (i64::MAX - 5).to_string()
+    );
+}
+
+#[test]
+fn test_append() {
+    assert_eq!(apply(&Append, None, &["1", "\"a\""]), r#"[1,"a"]"#);
+    assert_eq!(apply(&Append, Some("[true]"), &["[2]"]), "[true,[2]]");
+    // not a list, or not json, starts over
+    assert_eq!(apply(&Append, Some("{}"), &["1"]), "[1]");
+    assert_eq!(apply(&Append, Some("[]"), &["raw"]), r#"["raw"]"#);
+}
+
+#[test]
+fn test_max() {
+    assert_eq!(apply(&Max, None, &["3", "7", "5"]), "7");
+    assert_eq!(apply(&Max, Some("10"), &["2.5"]), "10");
+    assert_eq!(apply(&Max, Some("1.5"), &["2"]), "2");
+    assert_eq!(apply(&Max, Some("-1"), &["-2"]), "-1");
+    // a value that is not a number loses, an operand that is not one is ignored
+    assert_eq!(apply(&Max, Some("\"text\""), &["4"]), "4");
+    assert_eq!(apply(&Max, Some("4"), &["\"text\""]), "4");
+
+    // combined operands give the same result as applying them one by one
+    let key = "key".to_string();
+    let combined = Max.combine(&key, b"3", b"9").unwrap();
+    assert_eq!(Max.merge(&key, Some(b"5"), &combined), b"9");
+}
+
+#[test]
+fn test_set_union() {
+    assert_eq!(apply(&SetUnion, None, &["[1, 2]", "[2, 3]"]), "[1,2,3]");
+    assert_eq!(
+        apply(&SetUnion, Some(r#"["a"]"#), &[r#"["a", "b"]"#]),
+        r#"["a","b"]"#
+    );
+    // an operand that is not a list adds nothing
+    assert_eq!(apply(&SetUnion, Some("[1]"), &["2"]), "[1]");
+
+    let key = "key".to_string();
+    let combined = SetUnion.combine(&key, b"[1]", b"[2]").unwrap();
+    assert_eq!(SetUnion.merge(&key, Some(b"[2, 0]"), &combined), b"[2,0,1]");
+}
+
+// keeps the operand with the longest text
+struct Longest;
+
+impl MergeOperator for Longest {
+    fn merge(&self, _key: &Key, existing: Option<&[u8]>, operand: &[u8]) -> Value {
+        match existing {
+            Some(existing) if existing.len() >= operand.len() => existing.to_vec(),
+            _ => operand.to_vec(),
+        }
+    }
+}
+
+#[test]
+fn test_registered_operator_is_needed_to_replay() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = options(data_dir.path());
+    let key = "key".to_string();
+
+    {
+        let mut memtable = MemTable::new(options.clone());
+        memtable.register_merge_operator("longest", Longest);
+        memtable.startup().unwrap();
+
+        for operand in ["ab", "abcd", "a"] {
+            memtable
+                .merge(key.clone(), "longest", operand.as_bytes().to_vec())
+                .unwrap();
+        }
+        let Err(KvError::UnknownMergeOperator(_)) = memtable.merge(key.clone(), "other", vec![])
+        else {
+            panic!("an unknown operator was written");
+        };
+        assert_eq!(memtable.get(&key).unwrap(), Some(b"abcd".to_vec()));
+    }
+
+    // the log holds operands nothing could apply
+    let mut memtable = MemTable::new(options.clone());
+    let Err(KvError::UnknownMergeOperator(name)) = memtable.startup() else {
+        panic!("started without the operator");
+    };
+    assert_eq!(name, "longest");
+    drop(memtable);
+
+    let mut memtable = MemTable::new(options);
+    memtable.register_merge_operator("longest", Longest);
+    memtable.startup().unwrap();
+    assert_eq!(memtable.get(&key).unwrap(), Some(b"abcd".to_vec()));
+}
+
+#[test]
+fn test_long_increment_chain() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let memtable = open(&Options {
+        memtable_size_bytes: 64 * 1024 * 1024,
+        ..options(data_dir.path())
+    });
+
+    for _ in 0..20_000 {
+        memtable.increment("counter".into(), 1).unwrap();
+    }
+
+    // folding the operands one by one has to stay linear in their number
+    let started = Instant::now();
+    assert_eq!(
+        memtable.get(&"counter".to_string()).unwrap(),
+        Some(b"20000".to_vec())
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "reading 20000 operands took {:?}",
+        started.elapsed()
+    );
+}
+
+#[test]
+fn test_compaction_resolves_operands_no_deeper_file_holds() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let options = Options {
+        memtable_size_bytes: 8 * 1024,
+        level0_file_limit: 1,
+        level_base_bytes: 1,
+        max_levels: 3,
+        ..options(data_dir.path())
+    };
+    let stats = Arc::new(Stats::default());
+    let operators = MergeOperators::default();
+    let snapshots = Snapshots::default();
+
+    // the bottom level spans the counter, without holding it
+    {
+        let memtable = open(&options);
+        for i in 0..200 {
+            memtable.put(format!("a{i:04}"), vec![0; 100]).unwrap();
+            memtable.put(format!("z{i:04}"), vec![0; 100]).unwrap();
+        }
+    }
+    let manifest = Manifest::new(&options, stats.clone());
+    manifest.load().unwrap();
+    let mut strategy = LeveledCompaction::default();
+    while strategy
+        .compact(&manifest, &operators, &snapshots, &options, &stats)
+        .unwrap()
+    {}
+    drop(manifest);
+
+    {
+        let memtable = open(&options);
+        for _ in 0..1000 {
+            memtable.increment("m".into(), 1).unwrap();
+        }
+
+        let started = Instant::now();
+        while memtable.stats().flushes == 0 {
+            assert!(started.elapsed() < Duration::from_secs(30), "no flush ran");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // pushing level 0 down leaves a whole value, not a chain of operands
+    let manifest = Manifest::new(&options, stats.clone());
+    manifest.load().unwrap();
+    assert!(
+        manifest.levels()[2]
+            .iter()
+            .any(|sst| sst.in_range(&"m".into()))
+    );
+    assert!(
+        strategy
+            .compact(&manifest, &operators, &snapshots, &options, &stats)
+            .unwrap()
+    );
+
+    let levels = manifest.levels();
+    assert!(levels[0].is_empty());
+    let entries: Vec<_> = levels[1]
+        .iter()
+        .flat_map(|sst| sst.iter().unwrap())
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.key() == "m")
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].value().is_some());
+}
@@ -0,0 +1,100 @@
+use std::{fs, path::Path};
+
+use kv::{memtable::MemTable, options::Options};
+use serde_json::json;
+
+fn open(data_dir: &Path) -> MemTable {
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.to_path_buf(),
+        ..Options::default()
+    });
+    memtable.startup().unwrap();
+
+    memtable
+}
+
+// a log line as the json based first version wrote it, `entry` with its fields in their order
+fn wal_line(entry: &str) -> String {
+    let hash = crc32fast::hash(entry.as_bytes());
+
+    format!(r#"{{"hash":{hash},"entry":{entry}}}"#)
+}
+
+fn get(memtable: &MemTable, key: &str) -> Option<Vec<u8>> {
+    memtable.get(&key.to_string()).unwrap()
+}
+
+#[test]
+fn test_json_data_is_converted() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let sst_dir = data_dir.path().join("sst");
+    let wal_dir = data_dir.path().join("wal");
+    fs::create_dir_all(&sst_dir).unwrap();
+    fs::create_dir_all(&wal_dir).unwrap();
+
+    let older = json!([
+        { "key": "a", "value": 1 },
+        { "key": "b", "value": 2 },
+        { "key": "c", "value": 3 },
+    ]);
+    let newer = json!([
+        { "key": "a", "value": 10 },
+        { "key": "b", "deleted": true },
+    ]);
+    fs::write(sst_dir.join("sst-1.json"), older.to_string()).unwrap();
+    fs::write(sst_dir.join("sst-2.json"), newer.to_string()).unwrap();
+    fs::write(
+        sst_dir.join("manifest.txt"),
+        "data/sst/sst-1.json\ndata/sst/sst-2.json",
+    )
+    .unwrap();
+
+    let wal = [
+        wal_line(r#"{"op":"put","key":"d","value":4}"#),
+        wal_line(r#"{"op":"delete","key":"c"}"#),
+        // cut short by a crash
+        r#"{"hash":1,"entry":{"op":"put","key":"e""#.to_string(),
+    ];
+    fs::write(wal_dir.join("wal.db"), wal.join("\n")).unwrap();
+
+    {
+        let memtable = open(data_dir.path());
+        assert_eq!(get(&memtable, "a"), Some(b"10".to_vec()));
+        assert_eq!(get(&memtable, "b"), None);
+        assert_eq!(get(&memtable, "c"), None);
+        assert_eq!(get(&memtable, "d"), Some(b"4".to_vec()));
+        assert_eq!(get(&memtable, "e"), None);
+
+        // sequence numbers carry on after the converted ones
+        let version = memtable.put("f".into(), b"5".to_vec()).unwrap();
+        assert!(version > 2);
+    }
+
+    assert!(!wal_dir.join("wal.db").exists());
+    assert!(!sst_dir.join("sst-1.json").exists());
+
+    let memtable = open(data_dir.path());
+    assert_eq!(get(&memtable, "a"), Some(b"10".to_vec()));
+    assert_eq!(get(&memtable, "f"), Some(b"5".to_vec()));
+}
+
+#[test]
+fn test_corrupt_json_wal_stops_startup() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let wal_dir = data_dir.path().join("wal");
+    fs::create_dir_all(&wal_dir).unwrap();
+
+    let wal = [
+        wal_line(r#"{"op":"put","key":"a","value":1}"#),
+        r#"{"hash":1,"entry":{"op":"put","key":"b","value":2}}"#.to_string(),
+        wal_line(r#"{"op":"put","key":"c","value":3}"#),
+    ];
+    fs::write(wal_dir.join("wal.db"), wal.join("\n")).unwrap();
+
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.path().to_path_buf(),
+        ..Options::default()
+    });
+    assert!(memtable.startup().is_err());
+    assert!(wal_dir.join("wal.db").exists());
+}
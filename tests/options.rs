@@ -0,0 +1,87 @@
+use std::fs;
+
+use kv::{
+    error::KvError,
+    options::{CompactionStyle, Options},
+};
+
+fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn test_sources_apply_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("kv.toml");
+    fs::write(
+        &config,
+        "memtable_size_bytes = 1000\nmax_levels = 3\nsync_interval_ms = 7\n",
+    )
+    .unwrap();
+
+    let options = Options::from_sources(
+        vars(&[
+            ("KV_CONFIG", config.to_str().unwrap()),
+            ("KV_MAX_LEVELS", "4"),
+            ("KV_SYNC_INTERVAL_MS", "8"),
+            ("KV_UNRELATED", "ignored"),
+            ("PATH", "ignored"),
+        ]),
+        args(&["--sync-interval-ms", "9", "--compaction-style=full"]),
+    )
+    .unwrap();
+
+    // file over defaults, environment over file, flags over environment
+    assert_eq!(options.memtable_size_bytes, 1000);
+    assert_eq!(options.max_levels, 4);
+    assert_eq!(options.sync_interval_ms, 9);
+    assert_eq!(options.compaction_style, CompactionStyle::Full);
+    assert_eq!(
+        options.level0_file_limit,
+        Options::default().level0_file_limit
+    );
+}
+
+#[test]
+fn test_config_flag_picks_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let from_env = dir.path().join("env.toml");
+    let from_flag = dir.path().join("flag.toml");
+    fs::write(&from_env, "max_levels = 3\n").unwrap();
+    fs::write(&from_flag, "max_levels = 5\n").unwrap();
+
+    let options = Options::from_sources(
+        vars(&[("KV_CONFIG", from_env.to_str().unwrap())]),
+        args(&["--config", from_flag.to_str().unwrap()]),
+    )
+    .unwrap();
+
+    assert_eq!(options.max_levels, 5);
+}
+
+#[test]
+fn test_bad_values_are_rejected() {
+    for (name, value) in [
+        ("--memtable-size-bytes", "0"),
+        ("--level0-file-limit", "0"),
+        ("--max-levels", "1"),
+        ("--size-tier-ratio", "0.5"),
+        ("--sync-interval-ms", "0"),
+        ("--bloom-false-positive-rate", "1"),
+        ("--max-levels", "many"),
+        ("--no-such-option", "1"),
+    ] {
+        let result = Options::from_sources(vars(&[]), args(&[name, value]));
+
+        assert!(
+            matches!(result, Err(KvError::Config(_))),
+            "{name} {value} was accepted"
+        );
+    }
+}
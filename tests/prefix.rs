@@ -0,0 +1,87 @@
+use kv::{
+    memtable::MemTable,
+    options::{Options, SyncPolicy},
+};
+
+const KEYS: [&str; 12] = [
+    "a",
+    "ab",
+    "abc",
+    "abd",
+    "ac",
+    "a\u{10FFFF}",
+    "a\u{10FFFF}z",
+    "b",
+    "\u{D7FF}1",
+    "\u{E000}",
+    "\u{10FFFF}",
+    "\u{10FFFF}\u{10FFFF}",
+];
+
+fn open(data_dir: &std::path::Path) -> MemTable {
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.to_path_buf(),
+        sync_policy: SyncPolicy::None,
+        ..Options::default()
+    });
+    memtable.startup().unwrap();
+
+    for key in KEYS {
+        memtable.put(key.to_string(), b"1".to_vec()).unwrap();
+    }
+
+    memtable
+}
+
+fn scan_prefix(memtable: &MemTable, prefix: &str, start: Option<&str>) -> Vec<String> {
+    memtable
+        .scan_prefix(prefix, start.map(str::to_string).as_ref())
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect()
+}
+
+#[test]
+fn test_prefix_bounds() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let memtable = open(data_dir.path());
+
+    assert_eq!(scan_prefix(&memtable, "ab", None), ["ab", "abc", "abd"]);
+
+    // a prefix ending in the largest char ends where the char before it is bumped
+    assert_eq!(
+        scan_prefix(&memtable, "a\u{10FFFF}", None),
+        ["a\u{10FFFF}", "a\u{10FFFF}z"]
+    );
+
+    // one made only of the largest char has no upper bound
+    assert_eq!(
+        scan_prefix(&memtable, "\u{10FFFF}", None),
+        ["\u{10FFFF}", "\u{10FFFF}\u{10FFFF}"]
+    );
+
+    // the surrogate range is skipped over
+    assert_eq!(scan_prefix(&memtable, "\u{D7FF}", None), ["\u{D7FF}1"]);
+
+    // the empty prefix takes every key
+    assert_eq!(scan_prefix(&memtable, "", None).len(), KEYS.len());
+    assert_eq!(memtable.count_prefix("").unwrap(), KEYS.len());
+    assert_eq!(memtable.count_prefix("a").unwrap(), 7);
+    assert_eq!(memtable.count_prefix("x").unwrap(), 0);
+}
+
+#[test]
+fn test_prefix_cursor() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let memtable = open(data_dir.path());
+
+    assert_eq!(scan_prefix(&memtable, "ab", Some("abd")), ["abd"]);
+    assert_eq!(scan_prefix(&memtable, "ab", Some("abca")), ["abd"]);
+
+    // a cursor outside the prefix never leaves it
+    assert_eq!(
+        scan_prefix(&memtable, "ab", Some("a")),
+        ["ab", "abc", "abd"]
+    );
+    assert!(scan_prefix(&memtable, "ab", Some("b")).is_empty());
+}
@@ -0,0 +1,104 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use kv::{
+    memtable::MemTable,
+    options::{CompactionStyle, Options, SyncPolicy},
+};
+
+fn key(i: usize) -> String {
+    format!("key{i:04}")
+}
+
+// waits for the background workers to flush and compact at least once
+fn wait_for_compaction(memtable: &MemTable) {
+    let started = Instant::now();
+
+    while memtable.stats().compactions == 0 {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "no compaction ran"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn check_snapshot_reads(compaction_style: CompactionStyle) {
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.path().to_path_buf(),
+        memtable_size_bytes: 8 * 1024,
+        compaction_threshold: 500,
+        level0_file_limit: 2,
+        level_base_bytes: 16 * 1024,
+        size_tier_min_runs: 2,
+        compaction_style,
+        sync_policy: SyncPolicy::None,
+        ..Options::default()
+    });
+    memtable.startup().unwrap();
+
+    for i in 0..200 {
+        memtable.put(key(i), b"old".to_vec()).unwrap();
+    }
+    let snapshot = memtable.snapshot();
+
+    // every key is overwritten or deleted many times over, through flushes and compactions
+    for round in 0..20 {
+        for i in 0..200 {
+            if i % 7 == 0 {
+                memtable.delete(&key(i)).unwrap();
+            } else {
+                let value = format!("new {round}").into_bytes();
+                memtable.put(key(i), value).unwrap();
+            }
+        }
+    }
+    wait_for_compaction(&memtable);
+
+    for i in 0..200 {
+        assert_eq!(
+            memtable.get_at(&key(i), &snapshot).unwrap(),
+            Some(b"old".to_vec()),
+            "{compaction_style:?} {}",
+            key(i)
+        );
+
+        let latest = (i % 7 != 0).then(|| b"new 19".to_vec());
+        assert_eq!(memtable.get(&key(i)).unwrap(), latest);
+    }
+
+    let entries = memtable
+        .scan_at(&snapshot, None, None)
+        .unwrap()
+        .collect::<kv::error::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(entries.len(), 200);
+    assert!(entries.iter().all(|(_, value)| value == b"old"));
+
+    // once the snapshot is gone the old versions may be dropped, reads are unaffected
+    drop(snapshot);
+    let live = memtable
+        .scan(None, None)
+        .unwrap()
+        .collect::<kv::error::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(live.len(), 200 - 200usize.div_ceil(7));
+}
+
+#[test]
+fn test_snapshot_reads_with_leveled_compaction() {
+    check_snapshot_reads(CompactionStyle::Leveled);
+}
+
+#[test]
+fn test_snapshot_reads_with_size_tiered_compaction() {
+    check_snapshot_reads(CompactionStyle::SizeTiered);
+}
+
+#[test]
+fn test_snapshot_reads_with_full_compaction() {
+    check_snapshot_reads(CompactionStyle::Full);
+}
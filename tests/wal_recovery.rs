@@ -0,0 +1,272 @@
+use std::{collections::BTreeSet, fs, path::Path, sync::Arc, thread};
+
+use kv::{
+    batch::WriteBatch,
+    error::{KvError, Result},
+    memtable::MemTable,
+    options::{Options, WriteOptions},
+};
+
+fn open(data_dir: &Path, salvage_wal: bool) -> Result<MemTable> {
+    let mut memtable = MemTable::new(Options {
+        data_dir: data_dir.to_path_buf(),
+        salvage_wal,
+        ..Options::default()
+    });
+    memtable.startup()?;
+
+    Ok(memtable)
+}
+
+fn segment(data_dir: &Path, id: u64) -> std::path::PathBuf {
+    data_dir.join("wal").join(format!("wal-{id:06}.log"))
+}
+
+// offset of every record in a segment, after the magic
+fn record_offsets(data: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 8;
+    while offset < data.len() {
+        offsets.push(offset);
+        let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        offset += 4 + 4 + 1 + len;
+    }
+
+    offsets
+}
+
+fn get(memtable: &MemTable, key: &str) -> Option<Vec<u8>> {
+    memtable.get(&key.to_string()).unwrap()
+}
+
+#[test]
+fn test_torn_tail_is_cut_off() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        memtable.put("a".into(), b"1".to_vec()).unwrap();
+        memtable.put("b".into(), b"2".to_vec()).unwrap();
+    }
+
+    // the last record is cut short
+    let path = segment(data_dir.path(), 1);
+    let len = fs::metadata(&path).unwrap().len();
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        assert_eq!(get(&memtable, "a"), Some(b"1".to_vec()));
+        assert_eq!(get(&memtable, "b"), None);
+        memtable.put("c".into(), b"3".to_vec()).unwrap();
+    }
+
+    // the torn bytes are gone, writes after them replay
+    let memtable = open(data_dir.path(), false).unwrap();
+    assert_eq!(get(&memtable, "a"), Some(b"1".to_vec()));
+    assert_eq!(get(&memtable, "c"), Some(b"3".to_vec()));
+}
+
+#[test]
+fn test_zero_filled_tail_is_cut_off() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        memtable.put("a".into(), b"1".to_vec()).unwrap();
+    }
+
+    // space the file system allocated for a write that never landed
+    let path = segment(data_dir.path(), 1);
+    let mut data = fs::read(&path).unwrap();
+    let len = data.len();
+    data.extend_from_slice(&[0; 64]);
+    fs::write(&path, data).unwrap();
+
+    let memtable = open(data_dir.path(), false).unwrap();
+    assert_eq!(get(&memtable, "a"), Some(b"1".to_vec()));
+    assert_eq!(fs::metadata(&path).unwrap().len(), len as u64);
+}
+
+#[test]
+fn test_corruption_in_newest_segment() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        for key in ["a", "b", "c", "d", "e"] {
+            memtable.put(key.into(), b"1".to_vec()).unwrap();
+        }
+    }
+
+    // a flipped bit in the record of "b", with synced records after it
+    let path = segment(data_dir.path(), 1);
+    let mut data = fs::read(&path).unwrap();
+    let offset = record_offsets(&data)[1];
+    data[offset + 10] ^= 1;
+    fs::write(&path, &data).unwrap();
+
+    let Err(KvError::CorruptWal(_)) = open(data_dir.path(), false) else {
+        panic!("corrupt wal was replayed");
+    };
+    assert_eq!(fs::read(&path).unwrap(), data);
+
+    // salvaging skips the rest of the segment, but leaves it on disk
+    let memtable = open(data_dir.path(), true).unwrap();
+    assert_eq!(get(&memtable, "a"), Some(b"1".to_vec()));
+    assert_eq!(get(&memtable, "e"), None);
+    assert_eq!(fs::read(&path).unwrap(), data);
+}
+
+#[test]
+fn test_corruption_in_sealed_segment() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        for key in ["a", "b", "c"] {
+            memtable.put(key.into(), b"1".to_vec()).unwrap();
+        }
+    }
+
+    // segment 1 is sealed once the second run starts
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        memtable.put("d".into(), b"1".to_vec()).unwrap();
+    }
+
+    // a flipped bit in the record of "b"
+    let path = segment(data_dir.path(), 1);
+    let mut data = fs::read(&path).unwrap();
+    let offset = record_offsets(&data)[1];
+    data[offset + 10] ^= 1;
+    fs::write(&path, data).unwrap();
+
+    let Err(KvError::CorruptWal(_)) = open(data_dir.path(), false) else {
+        panic!("corrupt wal was replayed");
+    };
+
+    // salvaging loses the rest of the segment, and nothing after it
+    let memtable = open(data_dir.path(), true).unwrap();
+    assert_eq!(get(&memtable, "a"), Some(b"1".to_vec()));
+    assert_eq!(get(&memtable, "b"), None);
+    assert_eq!(get(&memtable, "c"), None);
+    assert_eq!(get(&memtable, "d"), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_record_inside_a_value_is_not_replayed() {
+    // the record of a put of "x", written to a log of its own
+    let other_dir = tempfile::tempdir().unwrap();
+    {
+        let memtable = open(other_dir.path(), false).unwrap();
+        memtable.put("x".into(), b"1".to_vec()).unwrap();
+    }
+    let record = fs::read(segment(other_dir.path(), 1)).unwrap()[8..].to_vec();
+
+    let data_dir = tempfile::tempdir().unwrap();
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        memtable.put("b".into(), record).unwrap();
+    }
+
+    // the record holding it is cut short at its header
+    let path = segment(data_dir.path(), 1);
+    let mut data = fs::read(&path).unwrap();
+    data[8] ^= 0xff;
+    fs::write(&path, data).unwrap();
+
+    let memtable = open(data_dir.path(), false).unwrap();
+    assert_eq!(get(&memtable, "b"), None);
+    assert_eq!(get(&memtable, "x"), None);
+}
+
+#[test]
+fn test_batch_is_replayed_whole_or_not_at_all() {
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let batch = |keys: &[&str]| {
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.put(key.to_string(), b"1".to_vec());
+        }
+
+        batch
+    };
+
+    {
+        let memtable = open(data_dir.path(), false).unwrap();
+        memtable
+            .write(batch(&["a", "b", "c"]), WriteOptions::default())
+            .unwrap();
+        memtable
+            .write(batch(&["d", "e", "f"]), WriteOptions::default())
+            .unwrap();
+    }
+
+    // the second batch loses the end of its last entry, its first ones are intact
+    let path = segment(data_dir.path(), 1);
+    let data = fs::read(&path).unwrap();
+    assert_eq!(record_offsets(&data).len(), 2);
+    fs::write(&path, &data[..data.len() - 5]).unwrap();
+
+    let memtable = open(data_dir.path(), false).unwrap();
+    for key in ["a", "b", "c"] {
+        assert_eq!(get(&memtable, key), Some(b"1".to_vec()), "{key}");
+    }
+    for key in ["d", "e", "f"] {
+        assert_eq!(get(&memtable, key), None, "{key}");
+    }
+}
+
+#[test]
+fn test_concurrent_writers_share_group_commits() {
+    const WRITERS: usize = 8;
+    const WRITES: usize = 50;
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let memtable = Arc::new(open(data_dir.path(), false).unwrap());
+
+    let handles: Vec<_> = (0..WRITERS)
+        .map(|writer| {
+            let memtable = memtable.clone();
+            thread::spawn(move || {
+                (0..WRITES)
+                    .map(|i| {
+                        let key = format!("{writer}-{i}");
+                        memtable.put(key, b"1".to_vec()).unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let versions: BTreeSet<_> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+
+    // every write got a version of its own, none was skipped
+    let total = (WRITERS * WRITES) as u64;
+    assert_eq!(versions, (1..=total).collect());
+
+    // each group is one wal write and one fsync, whatever its size
+    let stats = memtable.stats();
+    assert!(stats.wal_batches <= total);
+    assert_eq!(stats.wal_fsyncs, stats.wal_batches);
+    drop(memtable);
+
+    let memtable = open(data_dir.path(), false).unwrap();
+    for writer in 0..WRITERS {
+        for i in 0..WRITES {
+            assert_eq!(
+                get(&memtable, &format!("{writer}-{i}")),
+                Some(b"1".to_vec())
+            );
+        }
+    }
+}